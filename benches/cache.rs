@@ -0,0 +1,96 @@
+//! Lock contention of the cache under concurrent load: one mutex around a
+//! single LRU, as the cache used to be, against the 16 hash-selected shards
+//! `ShardedCache` uses now. Run with `cargo bench --bench cache`.
+//!
+//! The proxy is a binary crate, so this reproduces the locking pattern of
+//! a cache hit and a miss (lock, look up, lock, insert) rather than calling
+//! `ShardedCache` itself.
+
+use lru::LruCache;
+use std::hash::{BuildHasher, RandomState};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const CAPACITY: usize = 1000;
+const SHARDS: usize = 16;
+const TASKS: usize = 64;
+const OPS_PER_TASK: usize = 20_000;
+/// Keys requested across tasks; larger than `CAPACITY` so some lookups miss.
+const KEYS: usize = 1500;
+
+struct Cache {
+    shards: Vec<Mutex<LruCache<String, Arc<Vec<u8>>>>>,
+    hasher: RandomState,
+}
+
+impl Cache {
+    fn new(shards: usize) -> Self {
+        let per_shard = NonZeroUsize::new(CAPACITY.div_ceil(shards)).unwrap();
+        Self {
+            shards: (0..shards)
+                .map(|_| Mutex::new(LruCache::new(per_shard)))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<LruCache<String, Arc<Vec<u8>>>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    async fn lookup(&self, key: String, body: &Arc<Vec<u8>>) {
+        let hit = self.shard(&key).lock().await.get(&key).cloned();
+        if hit.is_none() {
+            self.shard(&key).lock().await.put(key, body.clone());
+        }
+    }
+}
+
+async fn run(shards: usize) -> Duration {
+    let cache = Arc::new(Cache::new(shards));
+    let body = Arc::new(vec![b'x'; 2048]);
+    let started = Instant::now();
+
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let cache = cache.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                for op in 0..OPS_PER_TASK {
+                    let key = format!(
+                        "https://api.song.link/v1-alpha.1/links?url={}",
+                        (task * 7919 + op) % KEYS
+                    );
+                    cache.lookup(key, &body).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let ops = (TASKS * OPS_PER_TASK) as f64;
+
+    for (name, shards) in [("single mutex", 1), ("sharded", SHARDS)] {
+        // The first round warms up the runtime and allocator.
+        runtime.block_on(run(shards));
+        let elapsed = runtime.block_on(run(shards));
+        println!(
+            "{:>12}: {:>8.1} ms, {:>6.0} ns/op",
+            name,
+            elapsed.as_secs_f64() * 1000.0,
+            elapsed.as_nanos() as f64 / ops
+        );
+    }
+}
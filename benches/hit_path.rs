@@ -0,0 +1,120 @@
+//! Cost of answering a cache hit: deep-cloning the cached `serde_json::Value`
+//! and serializing it again, as hits used to, against handing out the
+//! pre-serialized bytes the cache stores now. Run with
+//! `cargo bench --bench hit_path`.
+//!
+//! The proxy is a binary crate, so this reproduces both hit paths on a
+//! Songlink-sized payload rather than calling the handler.
+
+use axum::body::Bytes;
+use serde_json::{json, Value};
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20_000;
+
+/// A payload shaped like a Songlink answer for a track found on every
+/// platform, which is around 10 KB.
+fn payload() -> Value {
+    let platforms = [
+        "spotify",
+        "itunes",
+        "appleMusic",
+        "youtube",
+        "youtubeMusic",
+        "google",
+        "googleStore",
+        "pandora",
+        "deezer",
+        "tidal",
+        "amazonStore",
+        "amazonMusic",
+        "soundcloud",
+        "napster",
+        "yandex",
+        "spinrilla",
+        "audius",
+        "anghami",
+        "boomplay",
+        "audiomack",
+    ];
+    let links: serde_json::Map<String, Value> = platforms
+        .iter()
+        .map(|platform| {
+            (
+                platform.to_string(),
+                json!({
+                    "country": "US",
+                    "url": format!("https://{}.example/track/2TmqHjg7uhizGndzXQdFuf", platform),
+                    "entityUniqueId": format!("{}_SONG::2TmqHjg7uhizGndzXQdFuf", platform.to_uppercase()),
+                }),
+            )
+        })
+        .collect();
+    let entities: serde_json::Map<String, Value> = platforms
+        .iter()
+        .map(|platform| {
+            let id = format!("{}_SONG::2TmqHjg7uhizGndzXQdFuf", platform.to_uppercase());
+            (
+                id.clone(),
+                json!({
+                    "id": "2TmqHjg7uhizGndzXQdFuf",
+                    "type": "song",
+                    "title": "Never Gonna Give You Up",
+                    "artistName": "Rick Astley",
+                    "thumbnailUrl": format!("https://{}.example/images/2TmqHjg7uhizGndzXQdFuf/640x640.jpg", platform),
+                    "thumbnailWidth": 640,
+                    "thumbnailHeight": 640,
+                    "apiProvider": platform,
+                    "platforms": [platform],
+                }),
+            )
+        })
+        .collect();
+
+    json!({
+        "entityUniqueId": "SPOTIFY_SONG::2TmqHjg7uhizGndzXQdFuf",
+        "userCountry": "US",
+        "pageUrl": "https://song.link/s/2TmqHjg7uhizGndzXQdFuf",
+        "linksByPlatform": links,
+        "entitiesByUniqueId": entities,
+    })
+}
+
+fn time(mut hit: impl FnMut() -> usize) -> Duration {
+    // Warm up the allocator before measuring.
+    for _ in 0..ITERATIONS / 10 {
+        black_box(hit());
+    }
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(hit());
+    }
+    started.elapsed()
+}
+
+fn main() {
+    let value = Arc::new(payload());
+    let bytes = Arc::new(Bytes::from(serde_json::to_vec(&*value).unwrap()));
+    println!("payload: {} bytes", bytes.len());
+
+    let hits = [
+        (
+            "value",
+            time(|| {
+                let data = (*value).clone();
+                serde_json::to_vec(&data).unwrap().len()
+            }),
+        ),
+        ("bytes", time(|| (*bytes).clone().len())),
+    ];
+    for (name, elapsed) in hits {
+        println!(
+            "{:>6}: {:>8.1} ms, {:>8.0} ns/hit",
+            name,
+            elapsed.as_secs_f64() * 1000.0,
+            elapsed.as_nanos() as f64 / ITERATIONS as f64
+        );
+    }
+}
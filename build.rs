@@ -0,0 +1,39 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // HEAD only changes when switching branches; a new commit on the same
+    // branch updates the branch's ref, or packed-refs once refs are packed.
+    if let Some(branch) = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        let loose = format!(".git/{}", branch);
+        if std::path::Path::new(&loose).exists() {
+            println!("cargo:rerun-if-changed={}", loose);
+        } else {
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+
+    if std::env::var("GIT_COMMIT_HASH").is_err() {
+        let hash = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok());
+
+        if let Some(hash) = hash {
+            println!("cargo:rustc-env=GIT_COMMIT_HASH={}", hash.trim());
+        }
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+}
@@ -1,240 +1,4304 @@
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
+    body::Bytes,
+    error_handling::HandleErrorLayer,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        ConnectInfo, DefaultBodyLimit, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
-    Json, Router,
+    routing::{delete, get, post},
+    BoxError, Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::stream::{self, Stream, StreamExt};
+use ipnet::IpNet;
 use lru::LruCache;
-use reqwest::Client;
+use maxminddb::geoip2;
+use rand::Rng;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::future::IntoFuture;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
-use tower_http::cors::{Any, CorsLayer};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::sync::{oneshot, Mutex, Semaphore, SemaphorePermit};
+use tower::ServiceBuilder;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::{Level, Span};
+use tracing_subscriber::EnvFilter;
 
-const CACHE_SIZE: usize = 1000;
-const CACHE_TTL: Duration = Duration::from_secs(2_592_000);
+mod songlink;
+
+use songlink::{FetchError, Fetched, SonglinkClient, LINKS_PATH};
+
+const DEFAULT_CACHE_SIZE: usize = 1000;
+const DEFAULT_CACHE_TTL_SECS: u64 = 2_592_000;
+const DEFAULT_USER_COUNTRY: &str = "US";
+const SONGLINK_PAGE_BASE: &str = "https://song.link/";
+const DEFAULT_MAX_URL_LEN: usize = 2048;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 1024 * 1024;
+const MAX_QUERY_BYTES: usize = 8192;
+const MAX_LINK_DECODES: usize = 3;
+/// Fresh responses are cached downstream for less than their full TTL, so
+/// a `refresh=true` or purge reaches clients reasonably soon.
+const MISS_MAX_AGE: Duration = Duration::from_secs(3600);
+const DEFAULT_SLOW_LOG_MS: u64 = 2000;
+const DEFAULT_ROOT_REDIRECT: &str = "https://monochrome.tf";
+const DEFAULT_TTL_JITTER_PCT: u32 = 10;
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 300;
+const DEFAULT_EXPECTED_PLATFORMS: &str = "spotify,appleMusic,youtube";
+const DEFAULT_MAX_ENTRY_BYTES: usize = 1024 * 1024;
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+const READINESS_CACHE_TTL: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_INFLIGHT: usize = 64;
+const DEFAULT_MAX_QUEUED: u64 = 256;
+const INFLIGHT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(1);
+const BATCH_CONCURRENCY: usize = 8;
+const DEFAULT_MAX_BATCH_SIZE: usize = 50;
+const BATCH_TIMEOUT_FACTOR: u64 = 4;
+
+const PLATFORMS: &[&str] = &[
+    "spotify",
+    "itunes",
+    "appleMusic",
+    "youtube",
+    "youtubeMusic",
+    "google",
+    "googleStore",
+    "pandora",
+    "deezer",
+    "tidal",
+    "amazonStore",
+    "amazonMusic",
+    "soundcloud",
+    "napster",
+    "yandex",
+    "spinrilla",
+    "audius",
+    "anghami",
+    "boomplay",
+    "audiomack",
+    "bandcamp",
+];
+
+const ALLOWED_HOSTS: &[&str] = &[
+    "spotify.com",
+    "spotify.link",
+    "apple.com",
+    "youtube.com",
+    "youtu.be",
+    "google.com",
+    "pandora.com",
+    "deezer.com",
+    "deezer.page.link",
+    "tidal.com",
+    "amazon.com",
+    "amazon.co.uk",
+    "amazon.de",
+    "amazon.fr",
+    "amazon.it",
+    "amazon.es",
+    "amazon.ca",
+    "amazon.co.jp",
+    "amazon.com.au",
+    "amazon.com.br",
+    "soundcloud.com",
+    "napster.com",
+    "yandex.ru",
+    "yandex.com",
+    "spinrilla.com",
+    "audius.co",
+    "anghami.com",
+    "boomplay.com",
+    "audiomack.com",
+    "bandcamp.com",
+    "song.link",
+    "album.link",
+    "odesli.co",
+];
+
+const DEFAULT_NORMALIZE_RULES: &[(&str, &str)] = &[
+    ("https://monochrome.tf/#", "https://listen.tidal.com/"),
+    ("http://monochrome.tf/#", "https://listen.tidal.com/"),
+    ("https://monochrome.tf/%23", "https://listen.tidal.com/"),
+    ("http://monochrome.tf/%23", "https://listen.tidal.com/"),
+    (
+        "https://monochrome.prigoana.com/#",
+        "https://listen.tidal.com/",
+    ),
+    (
+        "http://monochrome.prigoana.com/#",
+        "https://listen.tidal.com/",
+    ),
+    (
+        "https://monochrome.prigoana.com/%23",
+        "https://listen.tidal.com/",
+    ),
+    (
+        "http://monochrome.prigoana.com/%23",
+        "https://listen.tidal.com/",
+    ),
+    ("https://tidal.squid.wtf/", "https://listen.tidal.com/"),
+    ("http://tidal.squid.wtf/", "https://listen.tidal.com/"),
+    ("https://tidal.qqdl.site/", "https://listen.tidal.com/"),
+    ("http://tidal.qqdl.site/", "https://listen.tidal.com/"),
+];
+
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0];
+
+const LATENCY_WINDOW: usize = 256;
+
+struct LatencyWindow {
+    samples_micros: Vec<AtomicU64>,
+    next: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests_total: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    upstream_errors: AtomicU64,
+    cache_evictions: AtomicU64,
+    cache_expired: AtomicU64,
+    cache_oversized: AtomicU64,
+    /// Successful upstream responses without the fields a lookup returns.
+    upstream_invalid: AtomicU64,
+    warm_pending: AtomicU64,
+    warm_completed: AtomicU64,
+    /// Fetches currently waiting for an upstream permit.
+    upstream_queued: AtomicU64,
+    upstream_latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    upstream_latency_count: AtomicU64,
+    upstream_latency_sum_micros: AtomicU64,
+    recent_latency: LatencyWindow,
+}
 
 #[derive(Clone)]
 struct CacheEntry {
-    data: Arc<serde_json::Value>,
+    status: StatusCode,
+    body: Bytes,
+    content_type: HeaderValue,
+    etag: String,
+    /// Songlink's own validators, sent back when the entry is refreshed.
+    upstream_etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    /// When the body was last fetched or revalidated, for the `Age` header.
+    stored_at: Instant,
     expires_at: Instant,
+    stale_until: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry {
+    key: String,
+    status: u16,
+    body: String,
+    content_type: String,
+    etag: String,
+    #[serde(default)]
+    upstream_etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    #[serde(default)]
+    stored_at: Option<u64>,
+    expires_at: u64,
+    stale_until: u64,
+}
+
+const CACHE_SHARDS: usize = 16;
+const REDIS_KEY_PREFIX: &str = "songlink-proxy:";
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+/// Keys per `DEL` when purging, so a large cache doesn't block Redis.
+const REDIS_DELETE_CHUNK: usize = 500;
+/// How long a count of the namespace's Redis keys is reused.
+const REDIS_COUNT_TTL: Duration = Duration::from_secs(5);
+
+struct ShardedCache {
+    shards: Vec<Mutex<CacheShard>>,
+    shard_capacity: usize,
+    /// Per-shard byte budget with `PROXY_CACHE_MAX_BYTES`; entries are then
+    /// evicted by size and `shard_capacity` doesn't apply.
+    shard_bytes: Option<usize>,
+    hasher: RandomState,
+}
+
+struct CacheShard {
+    entries: LruCache<String, CacheEntry>,
+    bytes: usize,
+}
+
+const RATE_LIMIT_TRACKED_CLIENTS: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<LruCache<IpAddr, TokenBucket>>,
+}
+
+/// W3C trace context of the incoming request, propagated to Songlink.
+#[derive(Clone, Copy)]
+struct TraceContext {
+    trace_id: u128,
+    flags: u8,
+}
+
+tokio::task_local! {
+    static TRACE_CONTEXT: TraceContext;
 }
 
+struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_OPEN_SECS: u64 = 30;
+
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+struct CircuitBreaker {
+    threshold: u32,
+    open_for: Duration,
+    /// Never held across an await, and also locked from `BreakerPass::drop`.
+    state: std::sync::Mutex<BreakerState>,
+}
+
+/// A fetch let through by the circuit breaker. Dropping it without
+/// `record`, because the fetch got no upstream permit or was cancelled,
+/// hands a half-open probe back so the next fetch can probe instead.
+struct BreakerPass<'a> {
+    breaker: &'a CircuitBreaker,
+    recorded: bool,
+}
+
+const DEFAULT_API_KEY_COOLDOWN_SECS: u64 = 60;
+
+struct ApiKeyPool {
+    keys: Vec<String>,
+    next: AtomicUsize,
+    benched_until: Mutex<Vec<Option<Instant>>>,
+    cooldown: Duration,
+}
+
+#[derive(Clone)]
+struct UpstreamResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    content_type: HeaderValue,
+    etag: String,
+    /// The URL actually requested, with the API key and any password
+    /// redacted; `None` when nothing was requested.
+    url: Option<String>,
+}
+
+impl UpstreamResponse {
+    /// Forwards a Songlink error status. Only negatively cached errors get a
+    /// `max_age`; everything else is sent with `no-store`.
+    fn into_error(mut self, cache_status: &'static str, max_age: Option<Duration>) -> ResolveError {
+        self.headers.insert(
+            HeaderName::from_static("x-cache"),
+            HeaderValue::from_static(cache_status),
+        );
+        if let Some(max_age) = max_age {
+            self.headers
+                .insert(header::CACHE_CONTROL, cache_control(max_age));
+        }
+        ResolveError::Upstream(self)
+    }
+}
+
+/// Machine-readable cause of an upstream failure, for client retry logic.
+#[derive(Clone, Copy, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    UpstreamTimeout,
+    UpstreamUnreachable,
+    UpstreamBadStatus,
+    ParseError,
+    ResponseTooLarge,
+    CircuitOpen,
+    Overloaded,
+}
+
+#[derive(Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+struct ErrorDetails {
+    upstream_status: u16,
+    body: String,
+}
+
+type FetchResult = Result<UpstreamResponse, FetchError>;
+
 struct AppState {
-    client: Client,
-    cache: Mutex<LruCache<String, CacheEntry>>,
+    songlink: SonglinkClient,
+    cache: Box<dyn CacheStore>,
+    cache_size: NonZeroUsize,
+    /// Byte budget of the in-memory cache, `PROXY_CACHE_MAX_BYTES`.
+    cache_max_bytes: Option<NonZeroUsize>,
+    cache_ttl: Duration,
+    /// Used instead of `cache_ttl` for payloads missing an expected platform.
+    partial_ttl: Duration,
+    expected_platforms: Vec<String>,
+    negative_ttl: Duration,
+    stale_grace: Duration,
+    ttl_jitter_pct: u32,
+    max_entry_bytes: usize,
+    max_url_len: usize,
+    max_batch_size: usize,
+    /// Lets `HEAD /api/links` fetch uncached links instead of answering `404`.
+    head_fetch: bool,
+    started_at: Instant,
+    draining: AtomicBool,
+    /// Unix seconds of the last `2xx` or `304` from an upstream, 0 if none.
+    last_upstream_success: AtomicU64,
+    /// Readiness fails once no upstream request has succeeded for this
+    /// long; zero disables the check.
+    upstream_success_window: Duration,
+    serve_stale_on_error: bool,
+    slow_log_threshold: Duration,
+    enable_debug: bool,
+    metrics: Metrics,
+    inflight: Mutex<HashMap<String, Shared<BoxFuture<'static, FetchResult>>>>,
+    rate_limiter: Option<RateLimiter>,
+    access_list: Option<AccessList>,
+    trusted_proxies: Vec<IpNet>,
+    admin_token: Option<String>,
+    default_country: Option<String>,
+    default_song_if_single: bool,
+    default_platform: Option<String>,
+    root_redirect: Option<HeaderValue>,
+    readiness: Mutex<Option<(Instant, bool)>>,
+    api_keys: Option<ApiKeyPool>,
+    normalize_rules: Vec<NormalizeRule>,
+    disable_normalization: bool,
+    geoip: Option<maxminddb::Reader<Vec<u8>>>,
+    upstream_permits: Semaphore,
+    /// Fetches allowed to wait for a permit before new ones are rejected.
+    max_queued: u64,
+    breaker: Option<CircuitBreaker>,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::IntoParams, utoipa::ToSchema),
+    into_params(parameter_in = Query)
+)]
 struct ProxyQuery {
+    /// Music link to resolve.
+    #[serde(default)]
     url: String,
+    /// Two-letter country code used to pick regional links.
     #[serde(rename = "userCountry")]
     user_country: Option<String>,
-    #[serde(rename = "songIfSingle")]
+    /// Resolve a single-track album to the track itself.
+    #[serde(rename = "songIfSingle", default, deserialize_with = "flag")]
     song_if_single: Option<bool>,
+    /// Platform identifier, used with `type` and `id` instead of `url`.
     platform: Option<String>,
+    /// Entity type, `song` or `album`.
     #[serde(rename = "type")]
     entity_type: Option<String>,
+    /// Platform-specific entity ID.
     id: Option<String>,
+    /// Songlink API key, used instead of the proxy's own keys.
     key: Option<String>,
+    /// Bypass the cache and fetch a fresh response.
+    #[serde(default, deserialize_with = "flag")]
+    refresh: Option<bool>,
+    /// Comma-separated list of platforms to keep in the response.
+    fields: Option<String>,
+    /// Return only `pageUrl` and a platform-to-URL map.
+    #[serde(default, deserialize_with = "flag")]
+    compact: Option<bool>,
+    /// Return Songlink's response unmodified (requires `PROXY_ENABLE_DEBUG`).
+    #[serde(default, deserialize_with = "flag")]
+    raw: Option<bool>,
+    /// Set for `HEAD` requests, which aren't counted as lookups and only
+    /// consult the cache unless `PROXY_HEAD_FETCH` is set.
+    #[serde(skip)]
+    head: bool,
+    /// Unrecognized parameters, forwarded to Songlink as-is.
+    #[serde(flatten)]
+    #[cfg_attr(feature = "openapi", param(ignore), schema(ignore))]
+    extra: HashMap<String, String>,
+}
+
+/// `#[serde(flatten)]` buffers query string values as strings, so boolean
+/// parameters have to accept both `true` and `"true"`.
+fn flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Str(String),
+    }
+
+    match Option::<Flag>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Flag::Bool(value)) => Ok(Some(value)),
+        Some(Flag::Str(value)) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid boolean: {}", value))),
+    }
+}
+
+#[derive(Deserialize)]
+struct NormalizeRule {
+    from: String,
+    to: String,
 }
 
 #[derive(Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 struct ErrorResponse {
     error: String,
     status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<ErrorDetails>,
+    /// Songlink's own error body, when its error status is passed through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<serde_json::Value>,
 }
 
-impl AppState {
-    fn new() -> Self {
+#[derive(Serialize)]
+struct Status {
+    uptime_secs: u64,
+    cache: CacheStatus,
+    upstream_latency: UpstreamLatency,
+    circuit_breaker: Option<&'static str>,
+    last_upstream_success: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CacheStatus {
+    entries: usize,
+    capacity: Option<usize>,
+    hit_ratio: f64,
+}
+
+#[derive(Serialize)]
+struct UpstreamLatency {
+    samples: usize,
+    p50_ms: Option<f64>,
+    p95_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    upstream_reachable: bool,
+    draining: bool,
+    last_upstream_success: Option<u64>,
+    upstream_stale: bool,
+}
+
+#[derive(Serialize)]
+struct DrainResponse {
+    draining: bool,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    build_timestamp: &'static str,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
+#[derive(Serialize)]
+struct CacheStats {
+    entries: usize,
+    capacity: Option<usize>,
+    hits: u64,
+    misses: u64,
+    hit_ratio: f64,
+    evictions: u64,
+    expired: u64,
+    too_large: u64,
+    warm_pending: u64,
+    warm_completed: u64,
+}
+
+/// A plain array resolves everything; the object form pages through it.
+#[derive(Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(untagged)]
+enum BatchRequest {
+    All(Vec<ProxyQuery>),
+    Page {
+        items: Vec<ProxyQuery>,
+        #[serde(default)]
+        offset: usize,
+        limit: Option<usize>,
+    },
+}
+
+#[derive(Serialize)]
+struct BatchPage {
+    total: usize,
+    offset: usize,
+    results: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct WarmResponse {
+    queued: usize,
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
         Self {
-            client: Client::builder()
-                .timeout(Duration::from_secs(30))
-                .gzip(true)
-                .brotli(true)
-                .deflate(true)
-                .pool_max_idle_per_host(10)
-                .pool_idle_timeout(Duration::from_secs(90))
-                .http2_keep_alive_interval(Some(Duration::from_secs(30)))
-                .http2_keep_alive_timeout(Duration::from_secs(20))
-                .build()
-                .expect("Failed to create HTTP client"),
-            cache: Mutex::new(LruCache::new(
-                NonZeroUsize::new(CACHE_SIZE).unwrap(),
-            )),
+            samples_micros: (0..LATENCY_WINDOW).map(|_| AtomicU64::new(0)).collect(),
+            next: AtomicUsize::new(0),
         }
     }
+}
 
-    fn normalize_url(url_str: &str) -> String {
-        let mut normalized = url_str.to_string();
-        
-        if normalized.contains("monochrome.tf") || normalized.contains("monochrome.prigoana.com") {
-            normalized = normalized
-                .replace("https://monochrome.tf/#", "https://listen.tidal.com/")
-                .replace("http://monochrome.tf/#", "https://listen.tidal.com/")
-                .replace("https://monochrome.tf/%23", "https://listen.tidal.com/")
-                .replace("http://monochrome.tf/%23", "https://listen.tidal.com/")
-                .replace("https://monochrome.prigoana.com/#", "https://listen.tidal.com/")
-                .replace("http://monochrome.prigoana.com/#", "https://listen.tidal.com/")
-                .replace("https://monochrome.prigoana.com/%23", "https://listen.tidal.com/")
-                .replace("http://monochrome.prigoana.com/%23", "https://listen.tidal.com/");
-        } else if normalized.contains("tidal.squid.wtf") {
-            normalized = normalized
-                .replace("https://tidal.squid.wtf/", "https://listen.tidal.com/")
-                .replace("http://tidal.squid.wtf/", "https://listen.tidal.com/");
-        } else if normalized.contains("tidal.qqdl.site") {
-            normalized = normalized
-                .replace("https://tidal.qqdl.site/", "https://listen.tidal.com/")
-                .replace("http://tidal.qqdl.site/", "https://listen.tidal.com/");
-        }
-        
-        normalized
-    }
-
-    fn build_api_url(params: &ProxyQuery) -> String {
-        let mut api_url = String::with_capacity(256);
-        api_url.push_str("https://api.song.link/v1-alpha.1/links?url=");
-        api_url.push_str(&urlencoding::encode(&params.url));
+impl LatencyWindow {
+    fn record(&self, elapsed: Duration) {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.samples_micros.len();
+        // Zero marks an unused slot, so round sub-microsecond samples up.
+        self.samples_micros[slot].store((elapsed.as_micros() as u64).max(1), Ordering::Relaxed);
+    }
 
-        if let Some(country) = &params.user_country {
-            api_url.push_str("&userCountry=");
-            api_url.push_str(country);
+    fn percentiles(&self) -> UpstreamLatency {
+        let mut samples: Vec<u64> = self
+            .samples_micros
+            .iter()
+            .map(|sample| sample.load(Ordering::Relaxed))
+            .filter(|&sample| sample > 0)
+            .collect();
+        samples.sort_unstable();
+
+        let percentile = |p: f64| {
+            let index = ((samples.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            samples.get(index).map(|&micros| micros as f64 / 1000.0)
+        };
+
+        UpstreamLatency {
+            samples: samples.len(),
+            p50_ms: percentile(0.50),
+            p95_ms: percentile(0.95),
+        }
+    }
+}
+
+impl Metrics {
+    fn observe_upstream_latency(&self, elapsed: Duration) {
+        self.recent_latency.record(elapsed);
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.upstream_latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.upstream_latency_count.fetch_add(1, Ordering::Relaxed);
+        self.upstream_latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::with_capacity(2048);
+
+        let counters = [
+            (
+                "songlink_proxy_requests_total",
+                "Total requests to /api/links",
+                &self.requests_total,
+            ),
+            (
+                "songlink_proxy_cache_hits_total",
+                "Requests served from the cache",
+                &self.cache_hits,
+            ),
+            (
+                "songlink_proxy_cache_misses_total",
+                "Requests that required an upstream fetch",
+                &self.cache_misses,
+            ),
+            (
+                "songlink_proxy_cache_evictions_total",
+                "Cache entries evicted to make room for new ones",
+                &self.cache_evictions,
+            ),
+            (
+                "songlink_proxy_cache_expired_total",
+                "Cache entries found expired on lookup",
+                &self.cache_expired,
+            ),
+            (
+                "songlink_proxy_upstream_errors_total",
+                "Failed upstream requests",
+                &self.upstream_errors,
+            ),
+            (
+                "songlink_proxy_upstream_invalid_total",
+                "Successful upstream responses rejected as malformed and not cached",
+                &self.upstream_invalid,
+            ),
+        ];
+
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let name = "songlink_proxy_upstream_queue_depth";
+        let _ = writeln!(out, "# HELP {} Fetches waiting for an upstream slot", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            name,
+            self.upstream_queued.load(Ordering::Relaxed)
+        );
+
+        let name = "songlink_proxy_upstream_latency_seconds";
+        let _ = writeln!(out, "# HELP {} Upstream response latency", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, bound) in self.upstream_latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.upstream_latency_count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.upstream_latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+}
+
+impl CacheEntry {
+    /// Approximate memory held by an entry, for `PROXY_CACHE_MAX_BYTES`.
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.body.len() + self.content_type.len() + self.etag.len()
+    }
+
+    fn persist(&self, key: &str) -> Option<PersistedEntry> {
+        let now = Instant::now();
+        if self.stale_until <= now {
+            return None;
+        }
+
+        let unix_now = unix_time();
+        Some(PersistedEntry {
+            key: key.to_string(),
+            status: self.status.as_u16(),
+            body: std::str::from_utf8(&self.body).ok()?.to_string(),
+            content_type: self.content_type.to_str().ok()?.to_string(),
+            etag: self.etag.clone(),
+            upstream_etag: header_string(&self.upstream_etag),
+            last_modified: header_string(&self.last_modified),
+            stored_at: Some(unix_now.saturating_sub(self.stored_at.elapsed().as_secs())),
+            expires_at: unix_now + self.expires_at.duration_since(now).as_secs(),
+            stale_until: unix_now + self.stale_until.duration_since(now).as_secs(),
+        })
+    }
+}
+
+fn header_string(value: &Option<HeaderValue>) -> Option<String> {
+    value
+        .as_ref()
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+impl PersistedEntry {
+    fn restore(self) -> Option<(String, CacheEntry)> {
+        let unix_now = unix_time();
+        if self.stale_until <= unix_now {
+            return None;
         }
 
-        if let Some(song_if_single) = params.song_if_single {
-            api_url.push_str("&songIfSingle=");
-            api_url.push_str(if song_if_single { "true" } else { "false" });
+        let now = Instant::now();
+        let entry = CacheEntry {
+            status: StatusCode::from_u16(self.status).ok()?,
+            body: Bytes::from(self.body),
+            content_type: HeaderValue::from_str(&self.content_type).ok()?,
+            etag: self.etag,
+            upstream_etag: self.upstream_etag.and_then(|value| value.parse().ok()),
+            last_modified: self.last_modified.and_then(|value| value.parse().ok()),
+            stored_at: self
+                .stored_at
+                .and_then(|stored_at| {
+                    now.checked_sub(Duration::from_secs(unix_now.saturating_sub(stored_at)))
+                })
+                .unwrap_or(now),
+            expires_at: now + Duration::from_secs(self.expires_at.saturating_sub(unix_now)),
+            stale_until: now + Duration::from_secs(self.stale_until - unix_now),
+        };
+        Some((self.key, entry))
+    }
+}
+
+trait CacheStore: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>>;
+    /// Returns how many other entries the insert evicted to make room.
+    fn put(&self, key: String, entry: CacheEntry) -> BoxFuture<'_, usize>;
+    fn pop<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>>;
+    fn len(&self) -> BoxFuture<'_, usize>;
+    fn capacity(&self) -> Option<usize>;
+    /// Whether entries live in Redis, shared with other replicas.
+    fn is_shared(&self) -> bool;
+    fn clear(&self) -> BoxFuture<'_, usize>;
+    fn save<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>>;
+    fn load<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>>;
+}
+
+impl ShardedCache {
+    fn new(capacity: NonZeroUsize, max_bytes: Option<NonZeroUsize>) -> Self {
+        let shard_count = match max_bytes {
+            Some(_) => CACHE_SHARDS,
+            None => CACHE_SHARDS.min(capacity.get()),
+        };
+        let per_shard = NonZeroUsize::new(capacity.get().div_ceil(shard_count)).unwrap();
+
+        Self {
+            shards: (0..shard_count)
+                .map(|_| {
+                    Mutex::new(CacheShard {
+                        entries: match max_bytes {
+                            Some(_) => LruCache::unbounded(),
+                            None => LruCache::new(per_shard),
+                        },
+                        bytes: 0,
+                    })
+                })
+                .collect(),
+            shard_capacity: per_shard.get(),
+            shard_bytes: max_bytes.map(|max_bytes| (max_bytes.get() / shard_count).max(1)),
+            hasher: RandomState::new(),
         }
+    }
 
-        if let Some(platform) = &params.platform {
-            api_url.push_str("&platform=");
-            api_url.push_str(platform);
+    fn shard(&self, key: &str) -> &Mutex<CacheShard> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+impl CacheShard {
+    /// Returns how many other entries were evicted to make room.
+    fn push(&mut self, key: String, entry: CacheEntry, budget: Option<usize>) -> usize {
+        let size = entry.size(&key);
+        if budget.is_some_and(|budget| size > budget) {
+            return 0;
         }
 
-        if let Some(entity_type) = &params.entity_type {
-            api_url.push_str("&type=");
-            api_url.push_str(entity_type);
+        if let Some(previous) = self.entries.peek(&key) {
+            self.bytes -= previous.size(&key);
         }
+        self.bytes += size;
 
-        if let Some(id) = &params.id {
-            api_url.push_str("&id=");
-            api_url.push_str(id);
+        let mut evicted = 0;
+        if let Some((evicted_key, evicted_entry)) = self.entries.push(key.clone(), entry) {
+            if evicted_key != key {
+                self.bytes -= evicted_entry.size(&evicted_key);
+                evicted += 1;
+            }
         }
 
-        if let Some(key) = &params.key {
-            api_url.push_str("&key=");
-            api_url.push_str(key);
+        if let Some(budget) = budget {
+            while self.bytes > budget {
+                let Some((evicted_key, evicted_entry)) = self.entries.pop_lru() else {
+                    break;
+                };
+                self.bytes -= evicted_entry.size(&evicted_key);
+                evicted += 1;
+            }
         }
+        evicted
+    }
 
-        api_url
+    fn pop(&mut self, key: &str) -> Option<CacheEntry> {
+        let entry = self.entries.pop(key)?;
+        self.bytes -= entry.size(key);
+        Some(entry)
+    }
+
+    fn clear(&mut self) -> usize {
+        let cleared = self.entries.len();
+        self.entries.clear();
+        self.bytes = 0;
+        cleared
     }
 }
 
-async fn proxy_handler(
-    State(state): State<Arc<AppState>>,
-    Query(mut params): Query<ProxyQuery>,
-) -> Result<Json<serde_json::Value>, Response> {
-    params.url = AppState::normalize_url(&params.url);
-    
-    let cache_key = AppState::build_api_url(&params);
+impl CacheStore for ShardedCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>> {
+        async move { self.shard(key).lock().await.entries.get(key).cloned() }.boxed()
+    }
 
-    {
-        let mut cache = state.cache.lock().await;
-        if let Some(entry) = cache.get(&cache_key) {
-            if entry.expires_at > Instant::now() {
-                return Ok(Json((*entry.data).clone()));
+    fn put(&self, key: String, entry: CacheEntry) -> BoxFuture<'_, usize> {
+        async move {
+            let mut shard = self.shard(&key).lock().await;
+            shard.push(key, entry, self.shard_bytes)
+        }
+        .boxed()
+    }
+
+    fn pop<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>> {
+        async move { self.shard(key).lock().await.pop(key) }.boxed()
+    }
+
+    fn len(&self) -> BoxFuture<'_, usize> {
+        async move {
+            let mut len = 0;
+            for shard in &self.shards {
+                len += shard.lock().await.entries.len();
             }
+            len
         }
+        .boxed()
     }
 
-    let response = state
-        .client
-        .get(&cache_key)
-        .send()
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::BAD_GATEWAY,
-                Json(ErrorResponse {
-                    error: format!("Failed to fetch from Songlink API: {}", e),
-                    status: 502,
-                }),
-            )
-                .into_response()
-        })?;
+    fn capacity(&self) -> Option<usize> {
+        match self.shard_bytes {
+            Some(_) => None,
+            None => Some(self.shards.len() * self.shard_capacity),
+        }
+    }
 
-    let status = response.status();
+    fn is_shared(&self) -> bool {
+        false
+    }
 
-    let json: serde_json::Value = response.json().await.map_err(|e| {
-        (
-            StatusCode::BAD_GATEWAY,
-            Json(ErrorResponse {
-                error: format!("Failed to parse response: {}", e),
-                status: 502,
-            }),
-        )
-            .into_response()
-    })?;
-
-    if status.is_success() {
-        let arc_json = Arc::new(json.clone());
-        let mut cache = state.cache.lock().await;
-        cache.put(
-            cache_key,
-            CacheEntry {
-                data: arc_json,
-                expires_at: Instant::now() + CACHE_TTL,
-            },
-        );
-        Ok(Json(json))
-    } else {
-        Err((
-            StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
-            Json(json),
-        )
-            .into_response())
+    fn clear(&self) -> BoxFuture<'_, usize> {
+        async move {
+            let mut cleared = 0;
+            for shard in &self.shards {
+                cleared += shard.lock().await.clear();
+            }
+            cleared
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>> {
+        async move {
+            let mut persisted = Vec::new();
+            for shard in &self.shards {
+                let shard = shard.lock().await;
+                // Least recently used first, so reloading in order restores recency.
+                persisted.extend(
+                    shard
+                        .entries
+                        .iter()
+                        .rev()
+                        .filter_map(|(key, entry)| entry.persist(key)),
+                );
+            }
+
+            let tmp_path = format!("{}.tmp", path);
+            std::fs::write(&tmp_path, serde_json::to_vec(&persisted)?)?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(persisted.len())
+        }
+        .boxed()
+    }
+
+    fn load<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>> {
+        async move {
+            let persisted: Vec<PersistedEntry> = serde_json::from_slice(&std::fs::read(path)?)?;
+            let mut loaded = 0;
+            for (key, entry) in persisted.into_iter().filter_map(PersistedEntry::restore) {
+                CacheStore::put(self, key, entry).await;
+                loaded += 1;
+            }
+            Ok(loaded)
+        }
+        .boxed()
     }
 }
 
-async fn root_redirect() -> Response {
-    (
-        StatusCode::TEMPORARY_REDIRECT,
-        [(axum::http::header::LOCATION, "https://monochrome.tf")],
-    )
-        .into_response()
+struct RedisCache {
+    connection: ConnectionManager,
+    local: ShardedCache,
+    /// `songlink-proxy:`, plus `<PROXY_CACHE_NAMESPACE>:` when set.
+    prefix: String,
+    /// Last count of keys under `prefix` and when it was taken.
+    counted: Mutex<Option<(Instant, usize)>>,
 }
 
-async fn health_check() -> &'static str {
-    "OK"
+impl RedisCache {
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    async fn keys(&self) -> redis::RedisResult<Vec<String>> {
+        let mut connection = self.connection.clone();
+        let mut keys = Vec::new();
+        let mut iter = connection
+            // Cache keys are upstream URLs. `redis_prefix` refuses
+            // namespaces this pattern could match from the default prefix.
+            .scan_match::<_, String>(format!("{}http*", self.prefix))
+            .await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key?);
+        }
+        Ok(keys)
+    }
+
+    fn decode(value: &str) -> Option<CacheEntry> {
+        let persisted: PersistedEntry = serde_json::from_str(value).ok()?;
+        persisted.restore().map(|(_, entry)| entry)
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let state = Arc::new(AppState::new());
+impl CacheStore for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>> {
+        async move {
+            let mut connection = self.connection.clone();
+            match connection
+                .get::<_, Option<String>>(self.redis_key(key))
+                .await
+            {
+                Ok(Some(value)) => Self::decode(&value),
+                Ok(None) => self.local.get(key).await,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Redis GET failed, using local cache");
+                    self.local.get(key).await
+                }
+            }
+        }
+        .boxed()
+    }
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .expose_headers(Any);
+    fn put(&self, key: String, entry: CacheEntry) -> BoxFuture<'_, usize> {
+        async move {
+            let value = entry
+                .persist(&key)
+                .and_then(|persisted| serde_json::to_string(&persisted).ok());
+            let Some(value) = value else {
+                return self.local.put(key, entry).await;
+            };
+            let ttl = entry
+                .stale_until
+                .duration_since(Instant::now())
+                .as_secs()
+                .max(1);
 
-    let app = Router::new()
-        .route("/", get(root_redirect))
-        .route("/health", get(health_check))
-        .route("/api/links", get(proxy_handler))
-        .layer(cors)
-        .with_state(state);
+            let mut connection = self.connection.clone();
+            if let Err(e) = connection
+                .set_ex::<_, _, ()>(self.redis_key(&key), value, ttl)
+                .await
+            {
+                tracing::warn!(error = %e, "Redis SET failed, using local cache");
+                return self.local.put(key, entry).await;
+            }
+            0
+        }
+        .boxed()
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .expect("Failed to bind to port 3000");
+    fn pop<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<CacheEntry>> {
+        async move {
+            let mut connection = self.connection.clone();
+            let remote = match connection
+                .get_del::<_, Option<String>>(self.redis_key(key))
+                .await
+            {
+                Ok(value) => value.as_deref().and_then(Self::decode),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Redis GETDEL failed");
+                    None
+                }
+            };
+            let local = self.local.pop(key).await;
+            remote.or(local)
+        }
+        .boxed()
+    }
+
+    /// Counts only this namespace's keys. Walking them is too slow for
+    /// every `/status` request, so the count is reused for
+    /// `REDIS_COUNT_TTL`, and concurrent callers wait for one walk.
+    fn len(&self) -> BoxFuture<'_, usize> {
+        async move {
+            let mut counted = self.counted.lock().await;
+            let remote = match *counted {
+                Some((at, count)) if at.elapsed() < REDIS_COUNT_TTL => count,
+                _ => match self.keys().await {
+                    Ok(keys) => {
+                        *counted = Some((Instant::now(), keys.len()));
+                        keys.len()
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Redis SCAN failed");
+                        0
+                    }
+                },
+            };
+            drop(counted);
+            remote + self.local.len().await
+        }
+        .boxed()
+    }
 
-    println!("Songlink CORS Proxy running on http://0.0.0.0:3000");
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
 
-    axum::serve(listener, app)
-        .await
-        .expect("Failed to start server");
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn clear(&self) -> BoxFuture<'_, usize> {
+        async move {
+            let mut cleared = 0;
+            match self.keys().await {
+                Ok(keys) => {
+                    let mut connection = self.connection.clone();
+                    for chunk in keys.chunks(REDIS_DELETE_CHUNK) {
+                        match connection.del::<_, usize>(chunk).await {
+                            Ok(deleted) => cleared += deleted,
+                            Err(e) => {
+                                tracing::warn!(error = %e, "Redis DEL failed");
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "Redis SCAN failed"),
+            }
+            *self.counted.lock().await = None;
+            cleared + self.local.clear().await
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>> {
+        self.local.save(path)
+    }
+
+    fn load<'a>(&'a self, path: &'a str) -> BoxFuture<'a, std::io::Result<usize>> {
+        self.local.load(path)
+    }
+}
+
+async fn cache_store(
+    capacity: NonZeroUsize,
+    max_bytes: Option<NonZeroUsize>,
+) -> Box<dyn CacheStore> {
+    let local = ShardedCache::new(capacity, max_bytes);
+    let Ok(url) = std::env::var("PROXY_REDIS_URL") else {
+        return Box::new(local);
+    };
+    let prefix = redis_prefix();
+
+    let config = ConnectionManagerConfig::new()
+        .set_connection_timeout(Some(REDIS_TIMEOUT))
+        .set_response_timeout(Some(REDIS_TIMEOUT))
+        .set_number_of_retries(1);
+    let connection = match redis::Client::open(url.as_str()) {
+        Ok(client) => ConnectionManager::new_with_config(client, config).await,
+        Err(e) => Err(e),
+    };
+
+    match connection {
+        Ok(connection) => {
+            tracing::info!("Using Redis cache");
+            Box::new(RedisCache {
+                connection,
+                local,
+                prefix,
+                counted: Mutex::new(None),
+            })
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Redis unavailable, falling back to the in-memory cache");
+            Box::new(local)
+        }
+    }
+}
+
+/// Keys of the default namespace are `songlink-proxy:http...`, so a
+/// namespace starting with `http` would fall under its `http*` pattern and
+/// be purged along with it. A namespace with glob characters would in turn
+/// match other namespaces' keys, so both are refused.
+fn redis_prefix() -> String {
+    let namespace = std::env::var("PROXY_CACHE_NAMESPACE").unwrap_or_default();
+    if namespace.is_empty() {
+        return REDIS_KEY_PREFIX.to_string();
+    }
+
+    if namespace.to_ascii_lowercase().starts_with("http")
+        || namespace.contains(['*', '?', '[', ']', '\\'])
+    {
+        tracing::error!(
+            namespace,
+            "PROXY_CACHE_NAMESPACE must not start with \"http\" or contain *, ?, [, ] or \\"
+        );
+        std::process::exit(1);
+    }
+    format!("{}{}:", REDIS_KEY_PREFIX, namespace)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+impl CircuitBreaker {
+    fn from_env() -> Option<Self> {
+        let threshold = env_or("PROXY_BREAKER_THRESHOLD", DEFAULT_BREAKER_THRESHOLD);
+        if threshold == 0 {
+            return None;
+        }
+
+        Some(Self {
+            threshold,
+            open_for: Duration::from_secs(env_or(
+                "PROXY_BREAKER_OPEN_SECS",
+                DEFAULT_BREAKER_OPEN_SECS,
+            )),
+            state: std::sync::Mutex::new(BreakerState::Closed { failures: 0 }),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn allow(&self) -> Option<BreakerPass<'_>> {
+        let now = Instant::now();
+        let mut state = self.state();
+
+        let allowed = match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now >= until => {
+                *state = BreakerState::HalfOpen { since: now };
+                tracing::info!("Circuit breaker half-open, probing upstream");
+                true
+            }
+            BreakerState::HalfOpen { since } if now.duration_since(since) >= self.open_for => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        };
+        // Not `then_some`: a pass built and then discarded would drop while
+        // the lock is still held.
+        allowed.then(|| BreakerPass {
+            breaker: self,
+            recorded: false,
+        })
+    }
+
+    fn state_name(&self) -> &'static str {
+        match *self.state() {
+            BreakerState::Closed { .. } => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    fn record(&self, healthy: bool) {
+        let mut state = self.state();
+
+        if healthy {
+            if !matches!(*state, BreakerState::Closed { .. }) {
+                tracing::info!("Circuit breaker closed");
+            }
+            *state = BreakerState::Closed { failures: 0 };
+            return;
+        }
+
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::HalfOpen { .. } => self.threshold,
+            BreakerState::Open { .. } => return,
+        };
+
+        if failures >= self.threshold {
+            tracing::warn!(
+                failures,
+                open_secs = self.open_for.as_secs(),
+                "Circuit breaker opened"
+            );
+            *state = BreakerState::Open {
+                until: Instant::now() + self.open_for,
+            };
+        } else {
+            *state = BreakerState::Closed { failures };
+        }
+    }
+
+    /// Reopens a half-open breaker that is due for a probe right away.
+    fn release_probe(&self) {
+        let mut state = self.state();
+        if matches!(*state, BreakerState::HalfOpen { .. }) {
+            *state = BreakerState::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+impl BreakerPass<'_> {
+    fn record(mut self, healthy: bool) {
+        self.recorded = true;
+        self.breaker.record(healthy);
+    }
+}
+
+impl Drop for BreakerPass<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.release_probe();
+        }
+    }
+}
+
+impl TraceContext {
+    fn new() -> Self {
+        Self {
+            trace_id: rand::thread_rng().gen_range(1..=u128::MAX),
+            flags: 0,
+        }
+    }
+
+    /// Parses `traceparent` (`00-<trace id>-<parent id>-<flags>`), falling
+    /// back to a fresh trace when the header is missing or malformed.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let parsed = headers
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let parts: Vec<&str> = value.trim().split('-').collect();
+                let [version, trace_id, parent_id, flags] = parts[..] else {
+                    return None;
+                };
+                if version.len() != 2
+                    || version == "ff"
+                    || trace_id.len() != 32
+                    || parent_id.len() != 16
+                    || flags.len() != 2
+                {
+                    return None;
+                }
+
+                u64::from_str_radix(parent_id, 16)
+                    .ok()
+                    .filter(|id| *id != 0)?;
+                Some(Self {
+                    trace_id: u128::from_str_radix(trace_id, 16)
+                        .ok()
+                        .filter(|id| *id != 0)?,
+                    flags: u8::from_str_radix(flags, 16).ok()?,
+                })
+            });
+
+        parsed.unwrap_or_else(Self::new)
+    }
+
+    /// A `traceparent` for one outbound request, with a new span ID.
+    fn traceparent(&self) -> String {
+        let span_id = rand::thread_rng().gen_range(1..=u64::MAX);
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, span_id, self.flags
+        )
+    }
+}
+
+impl AccessList {
+    fn from_env() -> Option<Self> {
+        let allow = cidrs_from_env("PROXY_ALLOW_CIDRS");
+        let deny = cidrs_from_env("PROXY_DENY_CIDRS");
+
+        if allow.is_empty() && deny.is_empty() {
+            return None;
+        }
+
+        Some(Self { allow, deny })
+    }
+
+    /// Deny entries win over allow entries; an empty allow list admits
+    /// everyone not denied.
+    fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Accepts CIDR ranges and bare addresses. A typo here would silently open
+/// or close the proxy, so invalid entries stop it at startup.
+fn cidrs_from_env(name: &str) -> Vec<IpNet> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .unwrap_or_else(|_| {
+                    tracing::error!(name, entry, "Invalid CIDR");
+                    std::process::exit(1);
+                })
+        })
+        .collect()
+}
+
+impl ApiKeyPool {
+    fn from_env() -> Option<Self> {
+        let keys: Vec<String> = std::env::var("PROXY_API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        if keys.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            benched_until: Mutex::new(vec![None; keys.len()]),
+            keys,
+            next: AtomicUsize::new(0),
+            cooldown: Duration::from_secs(env_or(
+                "PROXY_API_KEY_COOLDOWN_SECS",
+                DEFAULT_API_KEY_COOLDOWN_SECS,
+            )),
+        })
+    }
+
+    async fn pick(&self) -> Option<(usize, &str)> {
+        let now = Instant::now();
+        let benched_until = self.benched_until.lock().await;
+
+        for _ in 0..self.keys.len() {
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % self.keys.len();
+            if benched_until[index].is_none_or(|until| until <= now) {
+                return Some((index, &self.keys[index]));
+            }
+        }
+
+        None
+    }
+
+    async fn bench(&self, index: usize) {
+        self.benched_until.lock().await[index] = Some(Instant::now() + self.cooldown);
+        tracing::warn!(
+            key_index = index,
+            cooldown_secs = self.cooldown.as_secs(),
+            "API key rate limited, benching"
+        );
+    }
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(LruCache::new(
+                NonZeroUsize::new(RATE_LIMIT_TRACKED_CLIENTS).unwrap(),
+            )),
+        }
+    }
+
+    /// Takes `cost` tokens, capped at a full bucket so an oversized charge
+    /// can still succeed once the bucket has refilled.
+    async fn check(&self, ip: IpAddr, cost: u32) -> Result<(), Duration> {
+        let capacity = self.per_minute as f64;
+        let cost = f64::from(cost).min(capacity);
+        let refill_per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.get_or_insert_mut(ip, || TokenBucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (cost - bucket.tokens) / refill_per_sec,
+            ))
+        }
+    }
+}
+
+fn load_normalize_rules() -> Vec<NormalizeRule> {
+    let Ok(path) = std::env::var("PROXY_NORMALIZE_RULES") else {
+        return DEFAULT_NORMALIZE_RULES
+            .iter()
+            .map(|(from, to)| NormalizeRule {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+            .collect();
+    };
+
+    let rules: Vec<NormalizeRule> = std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            tracing::error!(path, error = %e, "Failed to load PROXY_NORMALIZE_RULES");
+            std::process::exit(1);
+        });
+
+    if rules.iter().any(|rule| rule.from.is_empty()) {
+        tracing::error!(path, "Normalize rules must have a non-empty `from`");
+        std::process::exit(1);
+    }
+
+    tracing::info!(path, count = rules.len(), "Loaded URL normalization rules");
+    rules
+}
+
+fn load_geoip() -> Option<maxminddb::Reader<Vec<u8>>> {
+    let path = std::env::var("PROXY_GEOIP_DB").ok()?;
+
+    let reader = maxminddb::Reader::open_readfile(&path).unwrap_or_else(|e| {
+        tracing::error!(path, error = %e, "Failed to open PROXY_GEOIP_DB");
+        std::process::exit(1);
+    });
+
+    tracing::info!(path, "Loaded GeoIP database");
+    Some(reader)
+}
+
+fn root_redirect_target() -> Option<HeaderValue> {
+    let target =
+        std::env::var("PROXY_ROOT_REDIRECT").unwrap_or_else(|_| DEFAULT_ROOT_REDIRECT.to_string());
+
+    if target.is_empty() {
+        return None;
+    }
+
+    if reqwest::Url::parse(&target).is_err() {
+        tracing::warn!(target, "PROXY_ROOT_REDIRECT is not an absolute URL");
+    }
+
+    match HeaderValue::from_str(&target) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!(target, "Invalid PROXY_ROOT_REDIRECT, using default");
+            Some(HeaderValue::from_static(DEFAULT_ROOT_REDIRECT))
+        }
+    }
+}
+
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_sensitive_param(name) => format!("{}=REDACTED", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Hides the password of a URL such as `PROXY_UPSTREAM_BASE` before it is
+/// logged.
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("REDACTED"));
+            parsed.into()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Logged once at startup so operators can check that their environment
+/// took effect. Secrets are only reported as set or unset.
+fn log_config(
+    state: &AppState,
+    cors: &CorsConfig,
+    bind_addr: SocketAddr,
+    tls: bool,
+    request_timeout: Duration,
+) {
+    let cors_origins = if cors.origins.is_empty() {
+        "*".to_string()
+    } else {
+        cors.origins
+            .iter()
+            .filter_map(|origin| origin.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    tracing::info!(
+        %bind_addr,
+        tls,
+        upstream = %redact_url(state.songlink.root()),
+        fallback_upstream = state.songlink.fallback_root().map(redact_url),
+        cache_size = state.cache_size.get(),
+        cache_max_bytes = state.cache_max_bytes.map_or(0, NonZeroUsize::get),
+        cache_ttl_secs = state.cache_ttl.as_secs(),
+        partial_cache_ttl_secs = state.partial_ttl.as_secs(),
+        negative_ttl_secs = state.negative_ttl.as_secs(),
+        upstream_timeout_secs = state.songlink.timeout().as_secs(),
+        connect_timeout_secs = state.songlink.connect_timeout().map(|timeout| timeout.as_secs()),
+        request_timeout_secs = request_timeout.as_secs(),
+        cors_origins,
+        cors_credentials = cors.allow_credentials,
+        admin_token = state.admin_token.is_some(),
+        api_keys = state.api_keys.as_ref().map_or(0, |pool| pool.keys.len()),
+        rate_limit = state.rate_limiter.is_some(),
+        redis = state.cache.is_shared(),
+        "Effective configuration"
+    );
+}
+
+fn is_music_host(host: &str) -> bool {
+    ALLOWED_HOSTS.iter().any(|allowed| {
+        host == *allowed
+            || host
+                .strip_suffix(allowed)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+fn is_tracking_param(name: &str) -> bool {
+    name == "si" || name.starts_with("utm_")
+}
+
+const TIDAL_ENTITY_TYPES: &[&str] = &["track", "album", "artist", "playlist", "video"];
+
+/// Reduces Tidal and Spotify links to their entity ID form, e.g.
+/// `tidal.com/browse/album/1/track/2` to `listen.tidal.com/track/2` and
+/// `open.spotify.com/intl-de/track/x` to `open.spotify.com/track/x`.
+/// Returns `false` for links it doesn't recognise.
+fn canonicalize_entity(url: &mut reqwest::Url) -> bool {
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let (host, path) = if host == "tidal.com" || host.ends_with(".tidal.com") {
+        let Some(entity) = segments
+            .windows(2)
+            .rev()
+            .find(|pair| TIDAL_ENTITY_TYPES.contains(&pair[0].as_str()))
+        else {
+            return false;
+        };
+        ("listen.tidal.com", format!("/{}/{}", entity[0], entity[1]))
+    } else if host == "open.spotify.com" {
+        let entity: Vec<&str> = segments
+            .iter()
+            .map(String::as_str)
+            .skip_while(|segment| segment.starts_with("intl-") || *segment == "embed")
+            .collect();
+        let [kind, id] = entity[..] else {
+            return false;
+        };
+        ("open.spotify.com", format!("/{}/{}", kind, id))
+    } else {
+        return false;
+    };
+
+    let _ = url.set_scheme("https");
+    let _ = url.set_host(Some(host));
+    url.set_path(&path);
+    url.set_query(None);
+    true
+}
+
+/// Cleans up links on known music hosts: entity links are reduced to their
+/// ID form, and otherwise share-tracking parameters, the fragment, and a
+/// trailing slash are dropped. Returns `None` when nothing changed.
+fn canonical_music_url(url_str: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(url_str).ok()?;
+    // Other schemes are left for `validate_url` to reject, rather than
+    // being rewritten to `https` along with the entity path.
+    if !matches!(url.scheme(), "http" | "https")
+        || !is_music_host(&url.host_str()?.to_ascii_lowercase())
+    {
+        return None;
+    }
+
+    url.set_fragment(None);
+
+    if !canonicalize_entity(&mut url) {
+        if url.query_pairs().any(|(name, _)| is_tracking_param(&name)) {
+            let kept: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(name, _)| !is_tracking_param(name))
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            if kept.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(kept);
+            }
+        }
+
+        if url.path().len() > 1 && url.path().ends_with('/') {
+            let path = url.path().trim_end_matches('/').to_string();
+            url.set_path(&path);
+        }
+    }
+
+    (url.as_str() != url_str).then(|| url.to_string())
+}
+
+/// Some clients encode the link before building a query string that gets
+/// encoded again, so `url` arrives as `https%3A%2F%2F...`. Such values are
+/// decoded until they parse, so both forms share one cache key.
+fn decode_link(url_str: &str) -> String {
+    let mut url = url_str.to_string();
+    for _ in 0..MAX_LINK_DECODES {
+        if !url.contains('%') || reqwest::Url::parse(&url).is_ok() {
+            break;
+        }
+        match urlencoding::decode(&url) {
+            Ok(decoded) if reqwest::Url::parse(&decoded).is_ok() || decoded.contains('%') => {
+                url = decoded.into_owned();
+            }
+            _ => break,
+        }
+    }
+    url
+}
+
+fn is_sensitive_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with("key") || name.contains("token") || name.contains("secret")
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!(name, value, "Invalid configuration value, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+impl AppState {
+    async fn new() -> Self {
+        let cache_size = env_or(
+            "PROXY_CACHE_SIZE",
+            NonZeroUsize::new(DEFAULT_CACHE_SIZE).unwrap(),
+        );
+        let cache_max_bytes = NonZeroUsize::new(env_or("PROXY_CACHE_MAX_BYTES", 0));
+        let cache_ttl = Duration::from_secs(env_or("PROXY_CACHE_TTL_SECS", DEFAULT_CACHE_TTL_SECS));
+
+        Self {
+            songlink: SonglinkClient::from_env(),
+            cache: cache_store(cache_size, cache_max_bytes).await,
+            cache_size,
+            cache_max_bytes,
+            cache_ttl,
+            partial_ttl: Duration::from_secs(env_or(
+                "PROXY_PARTIAL_CACHE_TTL_SECS",
+                cache_ttl.as_secs(),
+            )),
+            expected_platforms: env_or(
+                "PROXY_EXPECTED_PLATFORMS",
+                DEFAULT_EXPECTED_PLATFORMS.to_string(),
+            )
+            .split(',')
+            .map(str::trim)
+            .filter(|platform| !platform.is_empty())
+            .map(str::to_string)
+            .collect(),
+            negative_ttl: Duration::from_secs(env_or(
+                "PROXY_NEGATIVE_TTL_SECS",
+                DEFAULT_NEGATIVE_TTL_SECS,
+            )),
+            stale_grace: Duration::from_secs(env_or("PROXY_STALE_GRACE_SECS", 0)),
+            ttl_jitter_pct: env_or("PROXY_CACHE_TTL_JITTER_PCT", DEFAULT_TTL_JITTER_PCT),
+            max_entry_bytes: env_or("PROXY_MAX_ENTRY_BYTES", DEFAULT_MAX_ENTRY_BYTES),
+            max_url_len: env_or("PROXY_MAX_URL_LEN", DEFAULT_MAX_URL_LEN),
+            max_batch_size: env_or("PROXY_MAX_BATCH_SIZE", DEFAULT_MAX_BATCH_SIZE),
+            head_fetch: env_or("PROXY_HEAD_FETCH", false),
+            started_at: Instant::now(),
+            draining: AtomicBool::new(false),
+            last_upstream_success: AtomicU64::new(0),
+            upstream_success_window: Duration::from_secs(env_or(
+                "PROXY_READY_UPSTREAM_WINDOW_SECS",
+                0,
+            )),
+            serve_stale_on_error: env_or("PROXY_SERVE_STALE_ON_ERROR", false),
+            enable_debug: env_or("PROXY_ENABLE_DEBUG", false),
+            slow_log_threshold: Duration::from_millis(env_or(
+                "PROXY_SLOW_LOG_MS",
+                DEFAULT_SLOW_LOG_MS,
+            )),
+            metrics: Metrics::default(),
+            inflight: Mutex::new(HashMap::new()),
+            access_list: AccessList::from_env(),
+            trusted_proxies: cidrs_from_env("PROXY_TRUSTED_PROXIES"),
+            rate_limiter: match env_or("PROXY_RATE_LIMIT", 0u32) {
+                0 => None,
+                per_minute => Some(RateLimiter::new(per_minute)),
+            },
+            admin_token: std::env::var("PROXY_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            root_redirect: root_redirect_target(),
+            readiness: Mutex::new(None),
+            api_keys: ApiKeyPool::from_env(),
+            normalize_rules: load_normalize_rules(),
+            disable_normalization: env_or("PROXY_DISABLE_NORMALIZATION", false),
+            geoip: load_geoip(),
+            breaker: CircuitBreaker::from_env(),
+            upstream_permits: Semaphore::new(
+                env_or("PROXY_MAX_INFLIGHT", DEFAULT_MAX_INFLIGHT).max(1),
+            ),
+            max_queued: env_or("PROXY_MAX_QUEUED", DEFAULT_MAX_QUEUED),
+            default_country: std::env::var("PROXY_DEFAULT_COUNTRY")
+                .ok()
+                .map(|country| country.trim().to_ascii_uppercase())
+                .filter(|country| !country.is_empty()),
+            default_song_if_single: env_or("PROXY_DEFAULT_SONG_IF_SINGLE", false),
+            default_platform: std::env::var("PROXY_DEFAULT_PLATFORM")
+                .ok()
+                .map(|platform| platform.trim().to_string())
+                .filter(|platform| !platform.is_empty()),
+        }
+    }
+
+    fn is_admin(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.admin_token else {
+            return true;
+        };
+
+        // Constant time, so response timing doesn't reveal how much of a
+        // guessed token was right.
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|candidate| candidate.ct_eq(token.as_bytes()).into())
+    }
+
+    fn last_upstream_success(&self) -> Option<u64> {
+        match self.last_upstream_success.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(at),
+        }
+    }
+
+    /// Counts from startup until the first success, so a new instance gets
+    /// a full window before it is reported stale.
+    fn upstream_stale(&self) -> bool {
+        if self.upstream_success_window.is_zero() {
+            return false;
+        }
+
+        let now = unix_time();
+        let since = self
+            .last_upstream_success()
+            .unwrap_or_else(|| now.saturating_sub(self.started_at.elapsed().as_secs()));
+        now.saturating_sub(since) > self.upstream_success_window.as_secs()
+    }
+
+    /// Lifetime of a successful payload. Songlink sometimes answers before
+    /// it has matched every platform, so payloads without a link for one of
+    /// the expected platforms get the partial TTL and are refetched sooner.
+    fn success_ttl(&self, body: &[u8]) -> Duration {
+        if self.partial_ttl == self.cache_ttl {
+            return self.cache_ttl;
+        }
+
+        let complete = serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|data| {
+                let links = data.get("linksByPlatform")?.as_object()?;
+                Some(
+                    self.expected_platforms
+                        .iter()
+                        .all(|platform| links.contains_key(platform)),
+                )
+            })
+            .unwrap_or(false);
+
+        if complete {
+            self.cache_ttl
+        } else {
+            self.partial_ttl
+        }
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// `X-Forwarded-For` is only honoured when the socket peer is a trusted
+    /// proxy. The header is walked from the right, skipping further trusted
+    /// hops, so the result is the last address no trusted proxy vouches for.
+    fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        let mut ip = peer.ip();
+        if !self.is_trusted_proxy(ip) {
+            return ip;
+        }
+
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            ip = hop;
+            if !self.is_trusted_proxy(ip) {
+                break;
+            }
+        }
+        ip
+    }
+
+    fn country_for(&self, ip: IpAddr) -> Option<String> {
+        let result = self.geoip.as_ref()?.lookup(ip).ok()?;
+        let record: geoip2::Country = result.decode().ok()??;
+        record.country.iso_code.map(str::to_string)
+    }
+
+    fn detect_country(&self, params: &mut ProxyQuery, headers: &HeaderMap, peer: SocketAddr) {
+        if params.user_country.as_deref().is_none_or(str::is_empty) {
+            if let Some(country) = self.country_for(self.client_ip(headers, peer)) {
+                params.user_country = Some(country);
+            }
+        }
+    }
+
+    /// Double-encoded links are still decoded with normalization disabled,
+    /// since that only undoes the client's encoding.
+    fn normalize_url(&self, url_str: &str) -> String {
+        let url_str = &decode_link(url_str);
+        if self.disable_normalization {
+            return url_str.to_string();
+        }
+
+        let rewritten = self
+            .normalize_rules
+            .iter()
+            .find_map(|rule| {
+                url_str
+                    .strip_prefix(rule.from.as_str())
+                    .map(|rest| format!("{}{}", rule.to, rest))
+            })
+            .unwrap_or_else(|| url_str.to_string());
+
+        canonical_music_url(&rewritten).unwrap_or(rewritten)
+    }
+
+    fn validate_url(url_str: &str) -> Result<(), String> {
+        let url = reqwest::Url::parse(url_str).map_err(|e| format!("Invalid url: {}", e))?;
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("Unsupported url scheme: {}", url.scheme()));
+        }
+
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        if is_music_host(&host) {
+            Ok(())
+        } else {
+            Err(format!("Unsupported music host: {}", host))
+        }
+    }
+
+    fn canonical_cache_key(base: &str, params: &ProxyQuery) -> String {
+        let mut pairs: Vec<(&str, String)> = vec![("url", params.url.clone())];
+
+        if let Some(country) = &params.user_country {
+            let country = country.to_ascii_uppercase();
+            if country != DEFAULT_USER_COUNTRY {
+                pairs.push(("userCountry", country));
+            }
+        }
+
+        if params.song_if_single == Some(true) {
+            pairs.push(("songIfSingle", "true".to_string()));
+        }
+
+        let optional = [
+            ("platform", &params.platform),
+            ("type", &params.entity_type),
+            ("id", &params.id),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value.as_deref().filter(|value| !value.is_empty()) {
+                pairs.push((name, value.to_string()));
+            }
+        }
+
+        for (name, value) in &params.extra {
+            if !value.is_empty() {
+                pairs.push((name.as_str(), value.clone()));
+            }
+        }
+
+        pairs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        let mut api_url = String::with_capacity(256);
+        api_url.push_str(base);
+        for (i, (name, value)) in pairs.iter().enumerate() {
+            api_url.push(if i == 0 { '?' } else { '&' });
+            api_url.push_str(&urlencoding::encode(name));
+            api_url.push('=');
+            api_url.push_str(&urlencoding::encode(value));
+        }
+
+        api_url
+    }
+
+    fn upstream_url(cache_key: &str, key: Option<&str>) -> String {
+        match key {
+            Some(key) => format!("{}&key={}", cache_key, urlencoding::encode(key)),
+            None => cache_key.to_string(),
+        }
+    }
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+    (
+        status,
+        [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(ErrorResponse {
+            error,
+            status: status.as_u16(),
+            error_code: None,
+            details: None,
+            upstream: None,
+        }),
+    )
+        .into_response()
+}
+
+fn cache_control(max_age: Duration) -> HeaderValue {
+    HeaderValue::from_str(&format!("public, max-age={}", max_age.as_secs())).unwrap()
+}
+
+fn compute_etag(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("W/\"{:016x}\"", hash)
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        })
+}
+
+fn unauthorized() -> Response {
+    error_response(
+        StatusCode::UNAUTHORIZED,
+        "Missing or invalid admin token".to_string(),
+    )
+}
+
+fn cached_response(resolved: Resolved) -> Response {
+    let mut response = (
+        [(header::CONTENT_TYPE, resolved.content_type)],
+        resolved.body,
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert("x-cache", HeaderValue::from_static(resolved.cache_status));
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, cache_control(resolved.max_age));
+    if let Ok(etag) = HeaderValue::from_str(&resolved.etag) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    if resolved.upstream_failed {
+        response.headers_mut().insert(
+            header::WARNING,
+            HeaderValue::from_static("110 - \"Response is Stale\""),
+        );
+    }
+    if let Some(age) = resolved.age {
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(age.as_secs()));
+    }
+    if let Some(url) = resolved
+        .upstream_url
+        .and_then(|url| HeaderValue::from_str(&url).ok())
+    {
+        response.headers_mut().insert("x-upstream-url", url);
+    }
+    response
+}
+
+fn jittered(ttl: Duration, percent: u32) -> Duration {
+    if percent == 0 {
+        return ttl;
+    }
+    let spread = f64::from(percent.min(100)) / 100.0;
+    ttl.mul_f64(1.0 + rand::thread_rng().gen_range(-spread..=spread))
+}
+
+/// Falls back to `PROXY_FALLBACK_UPSTREAM` when Songlink fails, times out,
+/// or is cut off by the circuit breaker, but not when the proxy itself is
+/// out of upstream permits.
+async fn fetch_upstream(
+    state: &AppState,
+    cache_key: &str,
+    client_key: Option<&str>,
+) -> FetchResult {
+    let result = fetch_primary(state, cache_key, client_key).await;
+
+    let failed = match &result {
+        Ok(upstream) => upstream.status.is_server_error(),
+        Err(e) => !matches!(e.code, ErrorCode::Overloaded),
+    };
+    let Some(fallback) = state.songlink.fallback_root().filter(|_| failed) else {
+        return result;
+    };
+
+    // The fallback shares the upstream permits, so a failing Songlink can't
+    // double the number of outbound requests in flight.
+    let Ok(_permit) = upstream_permit(state).await else {
+        return result;
+    };
+
+    tracing::warn!("Songlink request failed, trying fallback upstream");
+    match request_upstream(state, cache_key, Some(fallback), None).await {
+        Ok(upstream) if !upstream.status.is_server_error() => Ok(upstream),
+        _ => result,
+    }
+}
+
+async fn fetch_primary(state: &AppState, cache_key: &str, client_key: Option<&str>) -> FetchResult {
+    let pass = state
+        .breaker
+        .as_ref()
+        .map(|breaker| {
+            breaker.allow().ok_or_else(|| FetchError {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: ErrorCode::CircuitOpen,
+                message: "Songlink API is unavailable, circuit breaker open".to_string(),
+                details: None,
+            })
+        })
+        .transpose()?;
+
+    let _permit = upstream_permit(state).await?;
+
+    let result = request_upstream(state, cache_key, None, client_key).await;
+
+    if let Some(pass) = pass {
+        let healthy = matches!(&result, Ok(upstream) if !upstream.status.is_server_error());
+        pass.record(healthy);
+    }
+
+    result
+}
+
+/// Decrements the queue depth once a waiting fetch gets its permit, gives
+/// up, or is dropped.
+struct QueuedFetch<'a>(&'a AtomicU64);
+
+impl Drop for QueuedFetch<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes a free permit right away, otherwise waits in a bounded queue. A
+/// full queue rejects the fetch immediately so a slow Songlink turns into
+/// `503`s instead of an ever-growing pile of waiting tasks.
+async fn upstream_permit(state: &AppState) -> Result<SemaphorePermit<'_>, FetchError> {
+    let overloaded = || FetchError {
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        code: ErrorCode::Overloaded,
+        message: "Too many upstream requests in flight, try again later".to_string(),
+        details: None,
+    };
+
+    if let Ok(permit) = state.upstream_permits.try_acquire() {
+        return Ok(permit);
+    }
+
+    let queued = &state.metrics.upstream_queued;
+    let _slot = QueuedFetch(queued);
+    if queued.fetch_add(1, Ordering::Relaxed) >= state.max_queued {
+        return Err(overloaded());
+    }
+
+    tokio::time::timeout(INFLIGHT_ACQUIRE_TIMEOUT, state.upstream_permits.acquire())
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or_else(overloaded)
+}
+
+/// Songlink answered a conditional request with `304`, so the cached body
+/// is still current and only its lifetime needs extending.
+async fn revalidated(state: &AppState, cache_key: &str, entry: CacheEntry) -> UpstreamResponse {
+    tracing::debug!("Cached entry revalidated by Songlink");
+
+    let now = Instant::now();
+    let ttl = state.success_ttl(&entry.body);
+    let expires_at = now + jittered(ttl, state.ttl_jitter_pct);
+    let entry = CacheEntry {
+        stored_at: now,
+        expires_at,
+        stale_until: expires_at + state.stale_grace,
+        ..entry
+    };
+    state.cache.put(cache_key.to_string(), entry.clone()).await;
+
+    UpstreamResponse {
+        status: entry.status,
+        headers: HeaderMap::new(),
+        body: entry.body,
+        content_type: entry.content_type,
+        etag: entry.etag,
+        url: None,
+    }
+}
+
+/// `fallback` replaces the upstream base in the request URL. API keys are
+/// never sent to a fallback, since it isn't Songlink.
+async fn request_upstream(
+    state: &AppState,
+    cache_key: &str,
+    fallback: Option<&str>,
+    client_key: Option<&str>,
+) -> FetchResult {
+    let pooled_key = match &state.api_keys {
+        Some(pool) if client_key.is_none() && fallback.is_none() => pool.pick().await,
+        _ => None,
+    };
+
+    let key = client_key.or(pooled_key.map(|(_, key)| key));
+    let upstream_url = match fallback {
+        Some(base) => format!("{}{}", base, &cache_key[state.songlink.root().len()..]),
+        None => AppState::upstream_url(cache_key, key),
+    };
+    let redacted_url = match fallback {
+        Some(_) => redact_url(&upstream_url),
+        None => redact_url(&AppState::upstream_url(cache_key, key.map(|_| "REDACTED"))),
+    };
+
+    let previous = state
+        .cache
+        .get(cache_key)
+        .await
+        .filter(|entry| entry.status.is_success());
+    let mut conditional = HeaderMap::new();
+    if let Some(entry) = &previous {
+        if let Some(etag) = &entry.upstream_etag {
+            conditional.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            conditional.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    let fetched = state
+        .songlink
+        .fetch(&upstream_url, &conditional, &state.metrics)
+        .await;
+
+    if let (Some(pool), Some((index, _))) = (&state.api_keys, pooled_key) {
+        let status = match &fetched {
+            Ok(fetched) => Some(fetched.status.as_u16()),
+            Err(e) => e.details.as_ref().map(|details| details.upstream_status),
+        };
+        if status == Some(StatusCode::TOO_MANY_REQUESTS.as_u16()) {
+            pool.bench(index).await;
+        }
+    }
+
+    let Fetched {
+        status,
+        headers,
+        body,
+        content_type,
+        upstream_etag,
+        last_modified,
+    } = fetched?;
+
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        state
+            .last_upstream_success
+            .store(unix_time(), Ordering::Relaxed);
+    }
+
+    if status == StatusCode::NOT_MODIFIED {
+        if let Some(entry) = previous {
+            return Ok(UpstreamResponse {
+                url: Some(redacted_url),
+                ..revalidated(state, cache_key, entry).await
+            });
+        }
+    }
+
+    let etag = compute_etag(&body);
+
+    let lifetime = if status.is_success() && !valid_payload(&body) {
+        tracing::warn!(
+            bytes = body.len(),
+            "Malformed Songlink response, not caching"
+        );
+        state
+            .metrics
+            .upstream_invalid
+            .fetch_add(1, Ordering::Relaxed);
+        None
+    } else if status.is_success() {
+        Some((state.success_ttl(&body), state.stale_grace))
+    } else if status == StatusCode::NOT_FOUND && !state.negative_ttl.is_zero() {
+        Some((state.negative_ttl, Duration::ZERO))
+    } else {
+        None
+    };
+
+    if lifetime.is_some() && body.len() > state.max_entry_bytes {
+        tracing::debug!(bytes = body.len(), "Response too large to cache");
+        state
+            .metrics
+            .cache_oversized
+            .fetch_add(1, Ordering::Relaxed);
+    } else if let Some((ttl, grace)) = lifetime {
+        let now = Instant::now();
+        let expires_at = now + jittered(ttl, state.ttl_jitter_pct);
+        let evicted = state
+            .cache
+            .put(
+                cache_key.to_string(),
+                CacheEntry {
+                    status,
+                    body: body.clone(),
+                    content_type: content_type.clone(),
+                    etag: etag.clone(),
+                    upstream_etag,
+                    last_modified,
+                    stored_at: now,
+                    expires_at,
+                    stale_until: expires_at + grace,
+                },
+            )
+            .await;
+        state
+            .metrics
+            .cache_evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    Ok(UpstreamResponse {
+        status,
+        headers,
+        body,
+        content_type,
+        etag,
+        url: Some(redacted_url),
+    })
+}
+
+async fn fetch_coalesced(
+    state: &Arc<AppState>,
+    cache_key: &str,
+    client_key: Option<&str>,
+) -> FetchResult {
+    let fetch = {
+        let mut inflight = state.inflight.lock().await;
+        inflight
+            .entry(cache_key.to_string())
+            .or_insert_with(|| {
+                let state = state.clone();
+                let cache_key = cache_key.to_string();
+                let client_key = client_key.map(str::to_string);
+                async move {
+                    let result = fetch_upstream(&state, &cache_key, client_key.as_deref()).await;
+                    state.inflight.lock().await.remove(&cache_key);
+                    result
+                }
+                .boxed()
+                .shared()
+            })
+            .clone()
+    };
+
+    fetch.await
+}
+
+struct Resolved {
+    body: Bytes,
+    content_type: HeaderValue,
+    etag: String,
+    cache_status: &'static str,
+    /// Served from the cache because the upstream request failed.
+    upstream_failed: bool,
+    /// Set for `raw=true` debug requests answered from upstream, with the
+    /// API key redacted.
+    upstream_url: Option<String>,
+    /// Time since the cached body was fetched; `None` for fresh responses.
+    age: Option<Duration>,
+    /// `max-age` sent to clients and CDNs.
+    max_age: Duration,
+}
+
+impl Resolved {
+    fn with_json(self, data: &serde_json::Value) -> Self {
+        let body = Bytes::from(serde_json::to_vec(data).unwrap_or_default());
+        Self {
+            etag: compute_etag(&body),
+            body,
+            content_type: HeaderValue::from_static("application/json"),
+            ..self
+        }
+    }
+}
+
+enum ResolveError {
+    Proxy(StatusCode, String),
+    Fetch(FetchError),
+    Upstream(UpstreamResponse),
+}
+
+impl ResolveError {
+    fn to_error_response(&self) -> ErrorResponse {
+        match self {
+            ResolveError::Proxy(status, error) => ErrorResponse {
+                error: error.clone(),
+                status: status.as_u16(),
+                error_code: None,
+                details: None,
+                upstream: None,
+            },
+            ResolveError::Fetch(e) => ErrorResponse {
+                error: e.message.clone(),
+                status: e.status.as_u16(),
+                error_code: Some(e.code),
+                details: e.details.clone(),
+                upstream: None,
+            },
+            ResolveError::Upstream(upstream) => ErrorResponse {
+                error: format!("Songlink API returned status {}", upstream.status.as_u16()),
+                status: upstream.status.as_u16(),
+                error_code: Some(ErrorCode::UpstreamBadStatus),
+                details: None,
+                upstream: serde_json::from_slice(&upstream.body).ok(),
+            },
+        }
+    }
+}
+
+impl IntoResponse for ResolveError {
+    fn into_response(self) -> Response {
+        match self {
+            ResolveError::Proxy(status, error) => error_response(status, error),
+            ResolveError::Fetch(ref e) => {
+                let mut response = (
+                    e.status,
+                    [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+                    Json(self.to_error_response()),
+                )
+                    .into_response();
+                if matches!(e.code, ErrorCode::Overloaded) {
+                    response.headers_mut().insert(
+                        header::RETRY_AFTER,
+                        HeaderValue::from(INFLIGHT_ACQUIRE_TIMEOUT.as_secs().max(1)),
+                    );
+                }
+                response
+            }
+            ResolveError::Upstream(ref upstream) => (
+                upstream.status,
+                [(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+                upstream.headers.clone(),
+                Json(self.to_error_response()),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// `cost` is the number of lookups the request makes, one for everything
+/// but a batch.
+async fn enforce_rate_limit(
+    state: &AppState,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    cost: u32,
+) -> Result<(), Response> {
+    let Some(limiter) = &state.rate_limiter else {
+        return Ok(());
+    };
+
+    limiter
+        .check(state.client_ip(headers, peer), cost)
+        .await
+        .map_err(|retry_after| {
+            let mut response = error_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Rate limit exceeded".to_string(),
+            );
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs().max(1)),
+            );
+            response
+        })
+}
+
+fn filter_platforms(data: &mut serde_json::Value, fields: &str) {
+    let wanted: HashSet<&str> = fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .collect();
+
+    if let Some(links) = data
+        .get_mut("linksByPlatform")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        links.retain(|platform, _| wanted.contains(platform.as_str()));
+    }
+}
+
+/// The minimal shape of a lookup result. Songlink has been seen answering
+/// `200 {}` during incidents, which must not be cached as a real result.
+fn valid_payload(body: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body).is_ok_and(|data| {
+        data.get("pageUrl")
+            .is_some_and(serde_json::Value::is_string)
+            || data
+                .get("linksByPlatform")
+                .is_some_and(serde_json::Value::is_object)
+    })
+}
+
+/// Resolves relative and protocol-relative `pageUrl` and platform `url`
+/// values against song.link. Returns `false` when every link was already
+/// absolute, so the upstream body can be sent byte for byte.
+fn absolute_links(data: &mut serde_json::Value) -> bool {
+    let Ok(base) = reqwest::Url::parse(SONGLINK_PAGE_BASE) else {
+        return false;
+    };
+    let mut changed = false;
+
+    let mut absolutize = |value: Option<&mut serde_json::Value>| {
+        let Some(serde_json::Value::String(link)) = value else {
+            return;
+        };
+        if reqwest::Url::parse(link).is_ok() {
+            return;
+        }
+        if let Ok(absolute) = base.join(link) {
+            *link = absolute.into();
+            changed = true;
+        }
+    };
+
+    absolutize(data.get_mut("pageUrl"));
+    if let Some(links) = data
+        .get_mut("linksByPlatform")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        for link in links.values_mut() {
+            absolutize(link.get_mut("url"));
+        }
+    }
+
+    changed
+}
+
+fn compact(data: &serde_json::Value) -> serde_json::Value {
+    let links: serde_json::Map<String, serde_json::Value> = data
+        .get("linksByPlatform")
+        .and_then(serde_json::Value::as_object)
+        .map(|links| {
+            links
+                .iter()
+                .filter_map(|(platform, link)| {
+                    let url = link.get("url")?.as_str()?;
+                    Some((platform.clone(), serde_json::Value::from(url)))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    serde_json::json!({
+        "pageUrl": data.get("pageUrl").cloned().unwrap_or(serde_json::Value::Null),
+        "linksByPlatform": links,
+    })
+}
+
+async fn resolve(
+    state: &Arc<AppState>,
+    path: &str,
+    mut params: ProxyQuery,
+) -> Result<Resolved, ResolveError> {
+    let fields = params
+        .fields
+        .take()
+        .filter(|fields| !fields.trim().is_empty());
+    let compact_view = params.compact.take().unwrap_or(false);
+    let raw = params.raw.unwrap_or(false);
+    if raw && !state.enable_debug {
+        return Err(ResolveError::Proxy(
+            StatusCode::BAD_REQUEST,
+            "raw=true requires PROXY_ENABLE_DEBUG".to_string(),
+        ));
+    }
+    let resolved = lookup(state, path, params).await?;
+
+    if raw {
+        return Ok(resolved);
+    }
+    let Ok(mut data) = serde_json::from_slice::<serde_json::Value>(&resolved.body) else {
+        return Ok(resolved);
+    };
+
+    // The cache holds Songlink's bytes as sent, so links are only made
+    // absolute on the way out and `raw=true` stays verbatim.
+    let rewritten = absolute_links(&mut data);
+    if !rewritten && fields.is_none() && !compact_view {
+        return Ok(resolved);
+    }
+    if let Some(fields) = fields {
+        filter_platforms(&mut data, &fields);
+    }
+    if compact_view {
+        data = compact(&data);
+    }
+    Ok(resolved.with_json(&data))
+}
+
+/// `path` selects the Songlink endpoint, e.g. `LINKS_PATH`.
+async fn lookup(
+    state: &Arc<AppState>,
+    path: &str,
+    mut params: ProxyQuery,
+) -> Result<Resolved, ResolveError> {
+    if params.url.trim().is_empty() {
+        return Err(ResolveError::Proxy(
+            StatusCode::BAD_REQUEST,
+            "missing required parameter: url".to_string(),
+        ));
+    }
+
+    if params.url.len() > state.max_url_len {
+        return Err(ResolveError::Proxy(
+            StatusCode::BAD_REQUEST,
+            format!("url is longer than {} characters", state.max_url_len),
+        ));
+    }
+
+    params.url = state.normalize_url(&params.url);
+
+    AppState::validate_url(&params.url)
+        .map_err(|e| ResolveError::Proxy(StatusCode::BAD_REQUEST, e))?;
+
+    if params.user_country.as_deref().is_none_or(str::is_empty) {
+        params.user_country = state.default_country.clone();
+    }
+    params.song_if_single = params.song_if_single.or(Some(state.default_song_if_single));
+    if params.platform.as_deref().is_none_or(str::is_empty) {
+        params.platform = state.default_platform.clone();
+    }
+
+    let cache_key = AppState::canonical_cache_key(&state.songlink.endpoint(path), &params);
+    lookup_key(state, cache_key, params).await
+}
+
+async fn lookup_key(
+    state: &Arc<AppState>,
+    cache_key: String,
+    params: ProxyQuery,
+) -> Result<Resolved, ResolveError> {
+    let head = params.head;
+    let count = move |counter: &AtomicU64| {
+        if !head {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    count(&state.metrics.requests_total);
+
+    if !params.refresh.unwrap_or(false) {
+        let cached = state.cache.get(&cache_key).await;
+        if let Some(entry) = cached {
+            let now = Instant::now();
+            if entry.expires_at > now {
+                count(&state.metrics.cache_hits);
+                Span::current().record("cache", "HIT");
+                if !entry.status.is_success() {
+                    let max_age = entry.expires_at.saturating_duration_since(now);
+                    return Err(UpstreamResponse {
+                        status: entry.status,
+                        headers: HeaderMap::new(),
+                        body: entry.body,
+                        content_type: entry.content_type,
+                        etag: entry.etag,
+                        url: None,
+                    }
+                    .into_error("HIT", Some(max_age)));
+                }
+                return Ok(Resolved {
+                    body: entry.body,
+                    content_type: entry.content_type,
+                    etag: entry.etag,
+                    cache_status: "HIT",
+                    upstream_failed: false,
+                    upstream_url: None,
+                    age: Some(entry.stored_at.elapsed()),
+                    max_age: entry.expires_at.saturating_duration_since(now),
+                });
+            }
+
+            count(&state.metrics.cache_expired);
+
+            if entry.stale_until > now {
+                count(&state.metrics.cache_hits);
+                Span::current().record("cache", "STALE");
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let _ = fetch_coalesced(&state, &cache_key, params.key.as_deref()).await;
+                });
+                return Ok(Resolved {
+                    body: entry.body,
+                    content_type: entry.content_type,
+                    etag: entry.etag,
+                    cache_status: "STALE",
+                    upstream_failed: false,
+                    upstream_url: None,
+                    age: Some(entry.stored_at.elapsed()),
+                    max_age: Duration::ZERO,
+                });
+            }
+        }
+    }
+
+    count(&state.metrics.cache_misses);
+    Span::current().record("cache", "MISS");
+
+    if head && !state.head_fetch {
+        return Err(ResolveError::Proxy(
+            StatusCode::NOT_FOUND,
+            "Not cached".to_string(),
+        ));
+    }
+
+    let upstream = match fetch_coalesced(state, &cache_key, params.key.as_deref()).await {
+        Ok(upstream) if !upstream.status.is_server_error() => upstream,
+        failed => {
+            if let Err(e) = &failed {
+                tracing::warn!(error = %e.message, "Upstream request failed");
+            }
+            if let Some(resolved) = stale_fallback(state, &cache_key).await {
+                return Ok(resolved);
+            }
+            failed.map_err(ResolveError::Fetch)?
+        }
+    };
+
+    Span::current().record("upstream_status", upstream.status.as_u16());
+
+    if upstream.status.is_success() {
+        Ok(Resolved {
+            max_age: if valid_payload(&upstream.body) {
+                state.success_ttl(&upstream.body).min(MISS_MAX_AGE)
+            } else {
+                Duration::ZERO
+            },
+            body: upstream.body,
+            content_type: upstream.content_type,
+            etag: upstream.etag,
+            cache_status: "MISS",
+            upstream_failed: false,
+            upstream_url: upstream.url.filter(|_| params.raw.unwrap_or(false)),
+            age: None,
+        })
+    } else {
+        let negatively_cached =
+            upstream.status == StatusCode::NOT_FOUND && !state.negative_ttl.is_zero();
+        let max_age = negatively_cached.then(|| state.negative_ttl.min(MISS_MAX_AGE));
+        Err(upstream.into_error("MISS", max_age))
+    }
+}
+
+async fn stale_fallback(state: &AppState, cache_key: &str) -> Option<Resolved> {
+    if !state.serve_stale_on_error {
+        return None;
+    }
+
+    let entry = state
+        .cache
+        .get(cache_key)
+        .await
+        .filter(|entry| entry.status.is_success())?;
+
+    tracing::warn!("Serving stale cached response after upstream failure");
+    Span::current().record("cache", "STALE");
+    Some(Resolved {
+        body: entry.body,
+        content_type: entry.content_type,
+        etag: entry.etag,
+        cache_status: "STALE",
+        upstream_failed: true,
+        upstream_url: None,
+        age: Some(entry.stored_at.elapsed()),
+        max_age: Duration::ZERO,
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/api/links",
+    params(ProxyQuery),
+    responses(
+        (status = 200, description = "Songlink response, as returned by the links endpoint"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 404, description = "Songlink could not resolve the link", body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+        (status = 502, description = "Songlink request failed", body = ErrorResponse),
+        (status = 503, description = "Circuit breaker open", body = ErrorResponse),
+    )
+))]
+async fn proxy_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Result<Query<ProxyQuery>, QueryRejection>,
+) -> Result<Response, Response> {
+    let Query(params) = params.map_err(|e| error_response(e.status(), e.body_text()))?;
+    links_response(&state, &headers, peer, LINKS_PATH, params).await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/api/links",
+    request_body = ProxyQuery,
+    responses(
+        (status = 200, description = "Songlink response, as returned by the links endpoint"),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid parameters", body = ErrorResponse),
+        (status = 404, description = "Songlink could not resolve the link", body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+        (status = 502, description = "Songlink request failed", body = ErrorResponse),
+        (status = 503, description = "Circuit breaker open", body = ErrorResponse),
+    )
+))]
+async fn proxy_post_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Result<Json<ProxyQuery>, JsonRejection>,
+) -> Result<Response, Response> {
+    let Json(params) = params.map_err(|e| error_response(e.status(), e.body_text()))?;
+    links_response(&state, &headers, peer, LINKS_PATH, params).await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    head,
+    path = "/api/links",
+    params(ProxyQuery),
+    responses(
+        (status = 200, description = "The link is cached; headers match `GET` with an empty body"),
+        (status = 404, description = "The link is not cached and `PROXY_HEAD_FETCH` is off"),
+    )
+))]
+async fn proxy_head_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Result<Query<ProxyQuery>, QueryRejection>,
+) -> Result<Response, Response> {
+    let Query(mut params) = params.map_err(|e| error_response(e.status(), e.body_text()))?;
+    params.head = true;
+    // A refresh would skip the cache, which is all a cache-only HEAD reads.
+    params.refresh = None;
+    let resolved = resolve_for_client(&state, &headers, peer, LINKS_PATH, params).await?;
+    let length = resolved.body.len();
+    let mut response = conditional_response(&headers, resolved);
+    if response.status() == StatusCode::OK {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    Ok(response)
+}
+
+async fn links_response(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    path: &str,
+    params: ProxyQuery,
+) -> Result<Response, Response> {
+    let resolved = resolve_for_client(state, headers, peer, path, params).await?;
+    Ok(conditional_response(headers, resolved))
+}
+
+async fn resolve_for_client(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    path: &str,
+    mut params: ProxyQuery,
+) -> Result<Resolved, Response> {
+    enforce_rate_limit(state, headers, peer, 1).await?;
+    state.detect_country(&mut params, headers, peer);
+
+    resolve(state, path, params)
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
+fn conditional_response(headers: &HeaderMap, resolved: Resolved) -> Response {
+    if resolved.cache_status != "STALE" && etag_matches(headers, &resolved.etag) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, resolved.etag),
+                (
+                    HeaderName::from_static("x-cache"),
+                    resolved.cache_status.to_string(),
+                ),
+            ],
+            [(header::CACHE_CONTROL, cache_control(resolved.max_age))],
+        )
+            .into_response();
+    }
+
+    cached_response(resolved)
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/api/links/resolve",
+    params(ProxyQuery),
+    responses(
+        (status = 200, description = "`{ \"pageUrl\": ... }` for the link"),
+        (status = 502, description = "Songlink request failed", body = ErrorResponse),
+    )
+))]
+async fn page_url_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Result<Query<ProxyQuery>, QueryRejection>,
+) -> Result<Response, Response> {
+    let Query(params) = params.map_err(|e| error_response(e.status(), e.body_text()))?;
+    let resolved = resolve_for_client(&state, &headers, peer, LINKS_PATH, params).await?;
+
+    let page_url = serde_json::from_slice::<serde_json::Value>(&resolved.body)
+        .ok()
+        .and_then(|data| data.get("pageUrl")?.as_str().map(str::to_string))
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::BAD_GATEWAY,
+                "Songlink response did not include a pageUrl".to_string(),
+            )
+        })?;
+
+    let resolved = resolved.with_json(&serde_json::json!({ "pageUrl": page_url }));
+    Ok(conditional_response(&headers, resolved))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/api/links/platform/{platform}",
+    params(("platform" = String, Path, description = "Platform name, e.g. `spotify`"), ProxyQuery),
+    responses(
+        (status = 200, description = "The `linksByPlatform` entry for the platform"),
+        (status = 400, description = "Unknown platform", body = ErrorResponse),
+        (status = 404, description = "No link for this platform", body = ErrorResponse),
+        (status = 502, description = "Songlink request failed", body = ErrorResponse),
+    )
+))]
+async fn platform_link_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(platform): Path<String>,
+    params: Result<Query<ProxyQuery>, QueryRejection>,
+) -> Result<Response, Response> {
+    let Query(params) = params.map_err(|e| error_response(e.status(), e.body_text()))?;
+    if !PLATFORMS.contains(&platform.as_str()) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Unknown platform: {}", platform),
+        ));
+    }
+
+    let resolved = resolve_for_client(&state, &headers, peer, LINKS_PATH, params).await?;
+
+    let link = serde_json::from_slice::<serde_json::Value>(&resolved.body)
+        .ok()
+        .and_then(|mut data| {
+            data.get_mut("linksByPlatform")?
+                .get_mut(&platform)
+                .map(serde_json::Value::take)
+        })
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                format!("No {} link found for this URL", platform),
+            )
+        })?;
+
+    let resolved = resolved.with_json(&link);
+    Ok(conditional_response(&headers, resolved))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/api/links/batch",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "One Songlink response or error object per request, in order, wrapped with `total` and `offset` for paged requests"),
+        (status = 400, description = "More items than `PROXY_MAX_BATCH_SIZE`", body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+    )
+))]
+async fn batch_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Result<Json<BatchRequest>, JsonRejection>,
+) -> Result<Response, Response> {
+    let Json(request) = request.map_err(|e| error_response(e.status(), e.body_text()))?;
+
+    let (items, page) = match request {
+        BatchRequest::All(items) => (items, None),
+        BatchRequest::Page {
+            items,
+            offset,
+            limit,
+        } => (items, Some((offset, limit))),
+    };
+
+    // The whole submission counts, not just the requested page, so a client
+    // can't send thousands of links and page through them.
+    if items.len() > state.max_batch_size {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Batch of {} items exceeds the limit of {}",
+                items.len(),
+                state.max_batch_size
+            ),
+        ));
+    }
+
+    let total = items.len();
+    let (mut items, page) = match page {
+        None => (items, None),
+        Some((offset, limit)) => (
+            items
+                .into_iter()
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect(),
+            Some((total, offset)),
+        ),
+    };
+
+    let cost = u32::try_from(items.len()).unwrap_or(u32::MAX).max(1);
+    enforce_rate_limit(&state, &headers, peer, cost).await?;
+
+    for params in &mut items {
+        state.detect_country(params, &headers, peer);
+    }
+
+    let results: Vec<serde_json::Value> = resolve_many(&state, LINKS_PATH, items)
+        .map(|result| match result {
+            Ok(resolved) => {
+                serde_json::from_slice(&resolved.body).unwrap_or(serde_json::Value::Null)
+            }
+            Err(e) => {
+                serde_json::to_value(e.to_error_response()).unwrap_or(serde_json::Value::Null)
+            }
+        })
+        .collect()
+        .await;
+
+    Ok(match page {
+        None => Json(results).into_response(),
+        Some((total, offset)) => Json(BatchPage {
+            total,
+            offset,
+            results,
+        })
+        .into_response(),
+    })
+}
+
+/// Resolves `items` with at most `BATCH_CONCURRENCY` lookups in flight,
+/// yielding results in input order.
+fn resolve_many<'a>(
+    state: &'a Arc<AppState>,
+    path: &'a str,
+    items: Vec<ProxyQuery>,
+) -> impl Stream<Item = Result<Resolved, ResolveError>> + 'a {
+    stream::iter(items)
+        .map(move |params| resolve(state, path, params))
+        .buffered(BATCH_CONCURRENCY)
+}
+
+#[cfg(feature = "openapi")]
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    info(title = "Songlink CORS Proxy"),
+    paths(
+        proxy_handler,
+        proxy_head_handler,
+        proxy_post_handler,
+        page_url_handler,
+        platform_link_handler,
+        batch_handler
+    )
+)]
+struct ApiDoc;
+
+#[cfg(feature = "openapi")]
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(<ApiDoc as utoipa::OpenApi>::openapi())
+}
+
+/// Swagger UI is loaded from a CDN, so the binary only carries this page.
+#[cfg(feature = "openapi")]
+async fn swagger_ui() -> axum::response::Html<&'static str> {
+    axum::response::Html(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+<title>Songlink CORS Proxy</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"##,
+    )
+}
+
+async fn root_redirect(State(state): State<Arc<AppState>>) -> Response {
+    match &state.root_redirect {
+        Some(location) => (
+            StatusCode::TEMPORARY_REDIRECT,
+            [(axum::http::header::LOCATION, location.clone())],
+        )
+            .into_response(),
+        None => "Songlink CORS Proxy. See /api/links for usage.".into_response(),
+    }
+}
+
+/// Serves `/` when it is listed in `PROXY_ALIAS_ROUTES`. Clients of older
+/// proxies put the lookup on the root, while browsers visiting the bare
+/// root should still land on the redirect, so a non-empty `url` decides.
+async fn root_handler(
+    state: State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    params: Result<Query<ProxyQuery>, QueryRejection>,
+) -> Result<Response, Response> {
+    match params {
+        Ok(Query(params)) if !params.url.is_empty() => {
+            links_response(&state, &headers, peer, LINKS_PATH, params).await
+        }
+        _ => Ok(root_redirect(state).await),
+    }
+}
+
+/// Paths routed in `main` that an alias would collide with. `/` isn't
+/// listed, since aliasing it is handled by `root_handler`.
+const BUILT_IN_ROUTES: &[&str] = &[
+    "/health",
+    "/health/live",
+    "/health/ready",
+    "/version",
+    "/status",
+    "/metrics",
+    "/cache/stats",
+    "/cache/purge",
+    "/cache/warm",
+    "/admin/drain",
+    "/api/links",
+    "/api/links/batch",
+    "/api/links/resolve",
+    "/openapi.json",
+    "/docs",
+];
+
+/// Extra paths served by the links handler, for clients written against
+/// other Songlink proxies. Axum panics on a route registered twice, so
+/// clashes with built-in routes or repeated aliases are rejected here.
+fn alias_routes() -> Vec<String> {
+    let mut aliases: Vec<String> = Vec::new();
+    let entries = std::env::var("PROXY_ALIAS_ROUTES").unwrap_or_default();
+
+    for path in entries
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+    {
+        let problem = if !path.starts_with('/') || path.contains([':', '*']) {
+            Some("Invalid PROXY_ALIAS_ROUTES entry")
+        } else if BUILT_IN_ROUTES.contains(&path) {
+            Some("PROXY_ALIAS_ROUTES entry clashes with a built-in route")
+        } else if aliases.iter().any(|alias| alias == path) {
+            Some("PROXY_ALIAS_ROUTES entry is listed more than once")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            tracing::error!(path, "{}", problem);
+            std::process::exit(1);
+        }
+        aliases.push(path.to_string());
+    }
+    aliases
+}
+
+async fn health_check() -> &'static str {
+    "OK"
+}
+
+async fn upstream_reachable(state: &AppState) -> bool {
+    let mut readiness = state.readiness.lock().await;
+    if let Some((checked_at, reachable)) = *readiness {
+        if checked_at.elapsed() < READINESS_CACHE_TTL {
+            return reachable;
+        }
+    }
+
+    let reachable = state.songlink.reachable(READINESS_CHECK_TIMEOUT).await;
+
+    *readiness = Some((Instant::now(), reachable));
+    reachable
+}
+
+async fn readiness_check(State(state): State<Arc<AppState>>) -> Response {
+    let upstream_reachable = upstream_reachable(&state).await;
+    let draining = state.draining.load(Ordering::Relaxed);
+    let upstream_stale = state.upstream_stale();
+    let ready = upstream_reachable && !draining && !upstream_stale;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Readiness {
+            ready,
+            upstream_reachable,
+            draining,
+            last_upstream_success: state.last_upstream_success(),
+            upstream_stale,
+        }),
+    )
+        .into_response()
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<Status> {
+    let hits = state.metrics.cache_hits.load(Ordering::Relaxed);
+    let misses = state.metrics.cache_misses.load(Ordering::Relaxed);
+    let lookups = hits + misses;
+
+    let circuit_breaker = state.breaker.as_ref().map(CircuitBreaker::state_name);
+
+    Json(Status {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        cache: CacheStatus {
+            entries: state.cache.len().await,
+            capacity: state.cache.capacity(),
+            hit_ratio: if lookups == 0 {
+                0.0
+            } else {
+                hits as f64 / lookups as f64
+            },
+        },
+        upstream_latency: state.metrics.recent_latency.percentiles(),
+        circuit_breaker,
+        last_upstream_success: state.last_upstream_success(),
+    })
+}
+
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("GIT_COMMIT_HASH").unwrap_or("unknown"),
+        build_timestamp: option_env!("BUILD_TIMESTAMP").unwrap_or("unknown"),
+    })
+}
+
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> Response {
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+/// Runs inside the request span so the trace ID lands in its log lines.
+async fn propagate_trace_context(request: Request, next: Next) -> Response {
+    let context = TraceContext::from_headers(request.headers());
+    Span::current().record("trace_id", format!("{:032x}", context.trace_id));
+    TRACE_CONTEXT.scope(context, next.run(request)).await
+}
+
+/// Errors that axum and tower-http produce on their own, such as unknown
+/// paths, unsupported methods, and bodies over `RequestBodyLimitLayer`'s
+/// limit, come empty or as plain text; this gives them the usual error JSON.
+async fn errors_as_json(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+    if is_json || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let error = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => "Request body is too large",
+        _ => status.canonical_reason().unwrap_or("Request failed"),
+    };
+    let mut json = error_response(status, error.to_string());
+    for (name, value) in response.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            json.headers_mut().append(name, value.clone());
+        }
+    }
+    json
+}
+
+async fn limit_query_length(request: Request, next: Next) -> Response {
+    if request
+        .uri()
+        .query()
+        .is_some_and(|query| query.len() > MAX_QUERY_BYTES)
+    {
+        return error_response(
+            StatusCode::URI_TOO_LONG,
+            format!("Query string is longer than {} bytes", MAX_QUERY_BYTES),
+        );
+    }
+
+    next.run(request).await
+}
+
+async fn enforce_access_list(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(access_list) = &state.access_list {
+        let ip = state.client_ip(request.headers(), peer);
+        if !access_list.permits(ip) {
+            tracing::info!(%ip, "Rejected by access list");
+            return error_response(StatusCode::FORBIDDEN, "Access denied".to_string());
+        }
+    }
+
+    next.run(request).await
+}
+
+/// Runs inside the request span, so the warning also carries the redacted
+/// query and `upstream_status` recorded there.
+async fn log_slow_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+
+    if !state.slow_log_threshold.is_zero() && elapsed > state.slow_log_threshold {
+        tracing::warn!(
+            status = response.status().as_u16(),
+            latency_ms = elapsed.as_millis() as u64,
+            "Slow request"
+        );
+    }
+
+    response
+}
+
+async fn handle_timeout_error(error: BoxError) -> Response {
+    if error.is::<tower::timeout::error::Elapsed>() {
+        error_response(StatusCode::GATEWAY_TIMEOUT, "Request timed out".to_string())
+    } else {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unhandled internal error: {}", error),
+        )
+    }
+}
+
+/// CORS settings as applied, after invalid origins have been dropped.
+struct CorsConfig {
+    /// Allowed origins; empty allows any origin.
+    origins: Vec<HeaderValue>,
+    allow_credentials: bool,
+}
+
+impl CorsConfig {
+    fn from_env() -> Self {
+        let origins: Vec<HeaderValue> = std::env::var("PROXY_CORS_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .filter_map(|origin| match HeaderValue::from_str(origin) {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    tracing::warn!(origin, "Ignoring invalid CORS origin");
+                    None
+                }
+            })
+            .collect();
+
+        let mut allow_credentials = env_or("PROXY_CORS_ALLOW_CREDENTIALS", false);
+        if allow_credentials && origins.is_empty() {
+            tracing::warn!(
+                "PROXY_CORS_ALLOW_CREDENTIALS requires PROXY_CORS_ORIGINS, credentials stay disabled"
+            );
+            allow_credentials = false;
+        }
+
+        Self {
+            origins,
+            allow_credentials,
+        }
+    }
+
+    fn layer(&self) -> CorsLayer {
+        if self.origins.is_empty() {
+            return CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods(Any)
+                .allow_headers(Any)
+                .expose_headers(Any);
+        }
+
+        let cors = CorsLayer::new().allow_origin(AllowOrigin::list(self.origins.clone()));
+
+        if self.allow_credentials {
+            cors.allow_credentials(true)
+                .allow_methods(AllowMethods::mirror_request())
+                .allow_headers(AllowHeaders::mirror_request())
+                .expose_headers([HeaderName::from_static("x-cache"), header::RETRY_AFTER])
+        } else {
+            cors.allow_methods(Any)
+                .allow_headers(Any)
+                .expose_headers(Any)
+        }
+    }
+}
+
+/// `PROXY_EXTRA_HEADERS` holds one `Name: Value` pair per line. A header
+/// that fails to parse stops the proxy at startup rather than going missing.
+fn extra_headers() -> Vec<(HeaderName, HeaderValue)> {
+    std::env::var("PROXY_EXTRA_HEADERS")
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let parsed = line.split_once(':').and_then(|(name, value)| {
+                Some((
+                    HeaderName::from_str(name.trim()).ok()?,
+                    HeaderValue::from_str(value.trim()).ok()?,
+                ))
+            });
+            parsed.unwrap_or_else(|| {
+                tracing::error!(line, "Invalid header in PROXY_EXTRA_HEADERS");
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+async fn tls_config() -> Option<RustlsConfig> {
+    let (cert, key) = match (
+        std::env::var("PROXY_TLS_CERT"),
+        std::env::var("PROXY_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        (Err(_), Err(_)) => return None,
+        _ => {
+            tracing::error!("PROXY_TLS_CERT and PROXY_TLS_KEY must be set together");
+            std::process::exit(1);
+        }
+    };
+
+    match RustlsConfig::from_pem_file(&cert, &key).await {
+        Ok(config) => Some(config),
+        Err(e) => {
+            tracing::error!(cert, key, error = %e, "Failed to load TLS certificate");
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn cache_stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<CacheStats>, Response> {
+    if !state.is_admin(&headers) {
+        return Err(unauthorized());
+    }
+
+    let entries = state.cache.len().await;
+    let capacity = state.cache.capacity();
+    let hits = state.metrics.cache_hits.load(Ordering::Relaxed);
+    let misses = state.metrics.cache_misses.load(Ordering::Relaxed);
+    let lookups = hits + misses;
+
+    Ok(Json(CacheStats {
+        entries,
+        capacity,
+        hits,
+        misses,
+        hit_ratio: if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 / lookups as f64
+        },
+        evictions: state.metrics.cache_evictions.load(Ordering::Relaxed),
+        expired: state.metrics.cache_expired.load(Ordering::Relaxed),
+        too_large: state.metrics.cache_oversized.load(Ordering::Relaxed),
+        warm_pending: state.metrics.warm_pending.load(Ordering::Relaxed),
+        warm_completed: state.metrics.warm_completed.load(Ordering::Relaxed),
+    }))
+}
+
+async fn cache_purge(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<PurgeResponse>, Response> {
+    if !state.is_admin(&headers) {
+        return Err(unauthorized());
+    }
+
+    let purged = state.cache.clear().await;
+    tracing::info!(purged, "Cache purged");
+
+    Ok(Json(PurgeResponse { purged }))
+}
+
+async fn cache_warm(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    request: Result<Json<Vec<String>>, JsonRejection>,
+) -> Result<(StatusCode, Json<WarmResponse>), Response> {
+    if !state.is_admin(&headers) {
+        return Err(unauthorized());
+    }
+    let Json(urls) = request.map_err(|e| error_response(e.status(), e.body_text()))?;
+
+    if urls.len() > state.max_batch_size {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Warm-up of {} links exceeds the limit of {}",
+                urls.len(),
+                state.max_batch_size
+            ),
+        ));
+    }
+
+    let queued = urls.len();
+    state
+        .metrics
+        .warm_pending
+        .fetch_add(queued as u64, Ordering::Relaxed);
+    tracing::info!(queued, "Cache warm-up started");
+
+    let items = urls
+        .into_iter()
+        .map(|url| ProxyQuery {
+            url,
+            ..Default::default()
+        })
+        .collect();
+
+    tokio::spawn(async move {
+        resolve_many(&state, LINKS_PATH, items)
+            .for_each(|_| async {
+                state.metrics.warm_pending.fetch_sub(1, Ordering::Relaxed);
+                state.metrics.warm_completed.fetch_add(1, Ordering::Relaxed);
+            })
+            .await;
+        tracing::info!(queued, "Cache warm-up finished");
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(WarmResponse { queued })))
+}
+
+async fn admin_drain(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<DrainResponse>, Response> {
+    if !state.is_admin(&headers) {
+        return Err(unauthorized());
+    }
+
+    if !state.draining.swap(true, Ordering::Relaxed) {
+        tracing::info!("Draining: readiness now reports unavailable");
+    }
+
+    Ok(Json(DrainResponse { draining: true }))
+}
+
+async fn cache_evict(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
+) -> Result<Json<PurgeResponse>, Response> {
+    if !state.is_admin(&headers) {
+        return Err(unauthorized());
+    }
+
+    match state.cache.pop(&key).await {
+        Some(_) => Ok(Json(PurgeResponse { purged: 1 })),
+        None => Err(error_response(
+            StatusCode::NOT_FOUND,
+            "Cache key not found".to_string(),
+        )),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let state = Arc::new(AppState::new().await);
+
+    let cache_file = std::env::var("PROXY_CACHE_FILE").ok();
+    if let Some(path) = &cache_file {
+        match state.cache.load(path).await {
+            Ok(count) => tracing::info!(path, count, "Restored cache from disk"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(path, error = %e, "Failed to restore cache from disk"),
+        }
+    }
+
+    let cors = CorsConfig::from_env();
+    let request_timeout = Duration::from_secs(env_or(
+        "PROXY_REQUEST_TIMEOUT_SECS",
+        state.songlink.timeout().as_secs(),
+    ));
+    let batch_timeout = Duration::from_secs(env_or(
+        "PROXY_BATCH_TIMEOUT_SECS",
+        request_timeout.as_secs() * BATCH_TIMEOUT_FACTOR,
+    ));
+    // Zero disables the limit, matching an unbounded upstream timeout.
+    let timeout = |duration: Duration| {
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handle_timeout_error))
+            .timeout(
+                Some(duration)
+                    .filter(|d| !d.is_zero())
+                    .unwrap_or(Duration::MAX),
+            )
+    };
+
+    let aliases = alias_routes();
+    let root = if aliases.iter().any(|path| path == "/") {
+        get(root_handler).layer(timeout(request_timeout))
+    } else {
+        get(root_redirect)
+    };
+
+    let routes = Router::new()
+        .route("/", root)
+        .route("/health", get(health_check))
+        .route("/health/live", get(health_check))
+        .route("/health/ready", get(readiness_check))
+        .route("/version", get(version))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics_handler))
+        .route("/cache/stats", get(cache_stats))
+        .route("/cache/purge", post(cache_purge))
+        .route("/cache/warm", post(cache_warm))
+        .route("/cache/:key", delete(cache_evict))
+        .route("/admin/drain", post(admin_drain))
+        .route(
+            "/api/links",
+            get(proxy_handler)
+                .head(proxy_head_handler)
+                .post(proxy_post_handler)
+                .layer(timeout(request_timeout)),
+        )
+        .route(
+            "/api/links/batch",
+            post(batch_handler).layer(timeout(batch_timeout)),
+        )
+        .route(
+            "/api/links/resolve",
+            get(page_url_handler).layer(timeout(request_timeout)),
+        )
+        .route(
+            "/api/links/platform/:platform",
+            get(platform_link_handler).layer(timeout(request_timeout)),
+        );
+
+    let routes = aliases
+        .iter()
+        .filter(|path| *path != "/")
+        .fold(routes, |routes, path| {
+            routes.route(
+                path,
+                get(proxy_handler)
+                    .head(proxy_head_handler)
+                    .post(proxy_post_handler)
+                    .layer(timeout(request_timeout)),
+            )
+        });
+
+    #[cfg(feature = "openapi")]
+    let routes = routes
+        .route("/openapi.json", get(openapi_json))
+        .route("/docs", get(swagger_ui));
+
+    let max_request_bytes = env_or("PROXY_MAX_REQUEST_BYTES", DEFAULT_MAX_REQUEST_BYTES);
+
+    let app = routes
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_request_bytes))
+        .layer(middleware::from_fn(errors_as_json))
+        .layer(middleware::from_fn(limit_query_length))
+        .layer(middleware::from_fn(propagate_trace_context))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            enforce_access_list,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            log_slow_requests,
+        ))
+        .layer(CompressionLayer::new())
+        .layer(cors.layer())
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<_>| {
+                    let request_id = request
+                        .headers()
+                        .get("x-request-id")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default();
+
+                    tracing::info_span!(
+                        "request",
+                        request_id,
+                        method = %request.method(),
+                        path = request.uri().path(),
+                        query = %redact_query(request.uri().query().unwrap_or_default()),
+                        cache = tracing::field::Empty,
+                        upstream_status = tracing::field::Empty,
+                        trace_id = tracing::field::Empty,
+                    )
+                })
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state.clone());
+
+    let app = extra_headers().into_iter().fold(app, |app, (name, value)| {
+        app.layer(SetResponseHeaderLayer::overriding(name, value))
+    });
+
+    let bind_addr: SocketAddr = match std::env::var("PROXY_BIND_ADDR") {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            tracing::error!(value, error = %e, "Invalid PROXY_BIND_ADDR");
+            std::process::exit(1);
+        }),
+        Err(_) => SocketAddr::from(([0, 0, 0, 0], 3000)),
+    };
+
+    let tls = tls_config().await;
+    log_config(&state, &cors, bind_addr, tls.is_some(), request_timeout);
+
+    let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind to {}: {}", bind_addr, e));
+
+    tracing::info!(
+        "Songlink CORS Proxy running on {}://{}",
+        if tls.is_some() { "https" } else { "http" },
+        listener.local_addr().unwrap_or(bind_addr)
+    );
+
+    let drain_timeout = Duration::from_secs(env_or("PROXY_DRAIN_TIMEOUT_SECS", 10));
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    tracing::info!(
+                        drain_timeout_secs = drain_timeout.as_secs(),
+                        "Shutdown signal received, draining in-flight requests"
+                    );
+                    handle.graceful_shutdown(Some(drain_timeout));
+                }
+            });
+
+            let listener = listener
+                .into_std()
+                .expect("Failed to convert the listener for TLS");
+            axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(service)
+                .await
+                .expect("Failed to start server");
+        }
+        None => {
+            let (draining_tx, draining_rx) = oneshot::channel();
+
+            let server = axum::serve(listener, service).with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                tracing::info!(
+                    drain_timeout_secs = drain_timeout.as_secs(),
+                    "Shutdown signal received, draining in-flight requests"
+                );
+                let _ = draining_tx.send(());
+            });
+
+            tokio::select! {
+                result = server.into_future() => result.expect("Failed to start server"),
+                _ = async {
+                    let _ = draining_rx.await;
+                    tokio::time::sleep(drain_timeout).await;
+                } => tracing::warn!("Drain timeout elapsed, aborting remaining requests"),
+            }
+        }
+    }
+
+    if let Some(path) = &cache_file {
+        match state.cache.save(path).await {
+            Ok(count) => tracing::info!(path, count, "Saved cache to disk"),
+            Err(e) => tracing::error!(path, error = %e, "Failed to save cache to disk"),
+        }
+    }
+
+    tracing::info!("Shutdown complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &str) -> CacheEntry {
+        let now = Instant::now();
+        CacheEntry {
+            status: StatusCode::OK,
+            body: Bytes::from(body.to_string()),
+            content_type: HeaderValue::from_static("application/json"),
+            etag: String::new(),
+            upstream_etag: None,
+            last_modified: None,
+            stored_at: now,
+            expires_at: now + Duration::from_secs(60),
+            stale_until: now + Duration::from_secs(60),
+        }
+    }
+
+    fn breaker(open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: 2,
+            open_for,
+            state: std::sync::Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Serializes tests that configure `AppState` through the environment,
+    /// which is shared by the whole test process.
+    static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    async fn state_with(env: &[(&str, &str)]) -> AppState {
+        let _guard = ENV_LOCK.lock().await;
+        for (name, value) in env {
+            std::env::set_var(name, value);
+        }
+        let state = AppState::new().await;
+        for (name, _) in env {
+            std::env::remove_var(name);
+        }
+        state
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_tracks_each_client_separately() {
+        let limiter = RateLimiter::new(2);
+        let client: IpAddr = [192, 0, 2, 1].into();
+        let other: IpAddr = [192, 0, 2, 2].into();
+
+        assert!(limiter.check(client, 1).await.is_ok());
+        assert!(limiter.check(client, 1).await.is_ok());
+        let retry_after = limiter.check(client, 1).await.unwrap_err();
+        assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+
+        assert!(limiter.check(other, 1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_is_only_read_from_trusted_peers() {
+        let state = state_with(&[("PROXY_TRUSTED_PROXIES", "10.0.0.0/8")]).await;
+        let forwarded = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        let trusted: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let untrusted: SocketAddr = "198.51.100.7:4000".parse().unwrap();
+        let client: IpAddr = "203.0.113.5".parse().unwrap();
+
+        let headers = forwarded("203.0.113.5");
+        assert_eq!(state.client_ip(&headers, untrusted), untrusted.ip());
+        assert_eq!(state.client_ip(&headers, trusted), client);
+        assert_eq!(state.client_ip(&HeaderMap::new(), trusted), trusted.ip());
+
+        // A client can prepend anything; only the hops the trusted proxies
+        // appended count.
+        let headers = forwarded("192.0.2.1, 203.0.113.5, 10.0.0.2");
+        assert_eq!(state.client_ip(&headers, trusted), client);
+    }
+
+    #[tokio::test]
+    async fn benched_api_keys_return_after_their_cooldown() {
+        let pool = ApiKeyPool {
+            keys: vec!["a".to_string(), "b".to_string()],
+            next: AtomicUsize::new(0),
+            benched_until: Mutex::new(vec![None; 2]),
+            cooldown: Duration::from_millis(50),
+        };
+        let pick = || async { pool.pick().await.map(|(_, key)| key.to_string()) };
+
+        assert_eq!(pick().await.as_deref(), Some("a"));
+        assert_eq!(pick().await.as_deref(), Some("b"));
+
+        pool.bench(0).await;
+        assert_eq!(pick().await.as_deref(), Some("b"));
+        assert_eq!(pick().await.as_deref(), Some("b"));
+        pool.bench(1).await;
+        assert_eq!(pick().await, None);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(pick().await.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn redundant_defaults_share_a_cache_key() {
+        let base = "https://api.song.link/v1-alpha.1/links";
+        let plain = ProxyQuery {
+            url: "https://open.spotify.com/track/1".to_string(),
+            ..Default::default()
+        };
+        let redundant = ProxyQuery {
+            url: plain.url.clone(),
+            user_country: Some("us".to_string()),
+            song_if_single: Some(false),
+            platform: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(
+            AppState::canonical_cache_key(base, &plain),
+            AppState::canonical_cache_key(base, &redundant)
+        );
+
+        let different = ProxyQuery {
+            url: plain.url.clone(),
+            user_country: Some("gb".to_string()),
+            ..Default::default()
+        };
+        assert_ne!(
+            AppState::canonical_cache_key(base, &plain),
+            AppState::canonical_cache_key(base, &different)
+        );
+    }
+
+    #[test]
+    fn cache_key_parameters_are_sorted() {
+        let base = "https://api.song.link/v1-alpha.1/links";
+        let params = ProxyQuery {
+            url: "https://open.spotify.com/track/1".to_string(),
+            user_country: Some("DE".to_string()),
+            song_if_single: Some(true),
+            extra: HashMap::from([
+                ("zeta".to_string(), "1".to_string()),
+                ("alpha".to_string(), "2".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            AppState::canonical_cache_key(base, &params),
+            "https://api.song.link/v1-alpha.1/links?alpha=2&songIfSingle=true\
+             &url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F1&userCountry=DE&zeta=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream_urls_use_the_route_path() {
+        let state = state_with(&[("PROXY_UPSTREAM_BASE", "https://mirror.example/v1/links")]).await;
+        let params = ProxyQuery {
+            url: "https://open.spotify.com/track/1".to_string(),
+            ..Default::default()
+        };
+
+        let links = AppState::canonical_cache_key(&state.songlink.endpoint(LINKS_PATH), &params);
+        assert_eq!(
+            links,
+            "https://mirror.example/v1/links?url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F1"
+        );
+        let search = AppState::canonical_cache_key(&state.songlink.endpoint("/search"), &params);
+        assert_eq!(
+            AppState::upstream_url(&search, Some("secret")),
+            "https://mirror.example/v1/search?url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F1\
+             &key=secret"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_as_json_keeps_every_header_value() {
+        use tower::Service;
+
+        let mut app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        axum::response::AppendHeaders([("link", "</a>"), ("link", "</b>")]),
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(errors_as_json));
+
+        let response = app
+            .call(Request::new(axum::body::Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let links: Vec<_> = response.headers().get_all("link").iter().collect();
+        assert_eq!(links, ["</a>", "</b>"]);
+    }
+
+    #[test]
+    fn compact_keeps_only_page_and_platform_urls() {
+        let full = serde_json::json!({
+            "entityUniqueId": "SPOTIFY_SONG::1",
+            "pageUrl": "https://song.link/s/1",
+            "linksByPlatform": {
+                "spotify": { "url": "https://open.spotify.com/track/1", "entityUniqueId": "SPOTIFY_SONG::1" },
+                "tidal": { "entityUniqueId": "TIDAL_SONG::1" }
+            },
+            "entitiesByUniqueId": {
+                "SPOTIFY_SONG::1": {
+                    "title": "Never Gonna Give You Up",
+                    "artistName": "Rick Astley",
+                    "thumbnailUrl": "https://i.scdn.co/image/1"
+                }
+            }
+        });
+
+        let compacted = compact(&full);
+        assert_eq!(
+            compacted,
+            serde_json::json!({
+                "pageUrl": "https://song.link/s/1",
+                "linksByPlatform": { "spotify": "https://open.spotify.com/track/1" }
+            })
+        );
+        assert!(
+            serde_json::to_vec(&compacted).unwrap().len() * 3
+                < serde_json::to_vec(&full).unwrap().len()
+        );
+
+        assert_eq!(
+            compact(&serde_json::json!({})),
+            serde_json::json!({ "pageUrl": null, "linksByPlatform": {} })
+        );
+    }
+
+    #[tokio::test]
+    async fn pool_settings_are_accepted_or_defaulted() {
+        for (max_idle, idle_timeout) in [("32", "15"), ("0", "0"), ("many", "-5")] {
+            let state = state_with(&[
+                ("PROXY_POOL_MAX_IDLE", max_idle),
+                ("PROXY_POOL_IDLE_TIMEOUT_SECS", idle_timeout),
+            ])
+            .await;
+            assert_eq!(
+                state.songlink.endpoint(LINKS_PATH),
+                "https://api.song.link/v1-alpha.1/links"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_size_and_ttl_come_from_the_environment() {
+        let state =
+            state_with(&[("PROXY_CACHE_SIZE", "160"), ("PROXY_CACHE_TTL_SECS", "60")]).await;
+        assert_eq!(state.cache.capacity(), Some(160));
+        assert_eq!(state.cache_ttl, Duration::from_secs(60));
+
+        let state =
+            state_with(&[("PROXY_CACHE_SIZE", "lots"), ("PROXY_CACHE_TTL_SECS", "-1")]).await;
+        assert_eq!(state.cache_size.get(), DEFAULT_CACHE_SIZE);
+        assert_eq!(state.cache_ttl, Duration::from_secs(DEFAULT_CACHE_TTL_SECS));
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.allow().unwrap().record(false);
+        assert_eq!(breaker.state_name(), "closed");
+        breaker.allow().unwrap().record(false);
+        assert_eq!(breaker.state_name(), "open");
+        assert!(breaker.allow().is_none());
+    }
+
+    #[test]
+    fn breaker_success_resets_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.allow().unwrap().record(false);
+        breaker.allow().unwrap().record(true);
+        breaker.allow().unwrap().record(false);
+        assert_eq!(breaker.state_name(), "closed");
+    }
+
+    #[test]
+    fn half_open_breaker_allows_one_probe() {
+        let breaker = breaker(Duration::ZERO);
+        breaker.record(false);
+        breaker.record(false);
+
+        let probe = breaker.allow().expect("probe after the open period");
+        assert_eq!(breaker.state_name(), "half_open");
+        probe.record(false);
+        assert_eq!(breaker.state_name(), "open");
+
+        breaker.allow().unwrap().record(true);
+        assert_eq!(breaker.state_name(), "closed");
+    }
+
+    #[test]
+    fn dropped_probe_is_handed_back() {
+        let breaker = breaker(Duration::from_secs(60));
+        *breaker.state() = BreakerState::Open {
+            until: Instant::now(),
+        };
+
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        drop(probe);
+        assert_eq!(breaker.state_name(), "open");
+        assert!(breaker.allow().is_some());
+    }
+
+    #[test]
+    fn byte_budget_evicts_as_many_entries_as_needed() {
+        let mut shard = CacheShard {
+            entries: LruCache::unbounded(),
+            bytes: 0,
+        };
+        // Each small entry is 1 + 10 + 16 bytes: key, body and content type.
+        let budget = Some(3 * 27);
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                shard.push(key.to_string(), entry(&"x".repeat(10)), budget),
+                0
+            );
+        }
+
+        let evicted = shard.push("d".to_string(), entry(&"x".repeat(30)), budget);
+        assert_eq!(evicted, 2);
+        assert!(shard.bytes <= 3 * 27);
+        assert!(shard.entries.contains("c"));
+        assert!(shard.entries.contains("d"));
+
+        assert_eq!(
+            shard.push("e".to_string(), entry(&"x".repeat(100)), budget),
+            0
+        );
+        assert!(!shard.entries.contains("e"));
+    }
+
+    #[tokio::test]
+    async fn sharded_cache_evicts_at_capacity() {
+        let cache = ShardedCache::new(NonZeroUsize::new(32).unwrap(), None);
+        assert_eq!(cache.capacity(), Some(32));
+
+        let mut evicted = 0;
+        for i in 0..100 {
+            evicted += cache.put(format!("k{}", i), entry("{}")).await;
+        }
+        let len = cache.len().await;
+        assert!(len <= 32);
+        assert_eq!(evicted + len, 100);
+        assert!(cache.get("k99").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn put_reports_every_eviction() {
+        // Each shard's budget only fits one of these entries.
+        let max_bytes = NonZeroUsize::new(CACHE_SHARDS * 40);
+        let cache = ShardedCache::new(NonZeroUsize::new(1000).unwrap(), max_bytes);
+
+        let mut evicted = 0;
+        for i in 0..100 {
+            evicted += cache.put(format!("k{:02}", i), entry("0123456789")).await;
+        }
+        let len = cache.len().await;
+        assert!(len <= CACHE_SHARDS);
+        assert_eq!(evicted + len, 100);
+    }
+
+    #[test]
+    fn jitter_spreads_expiries_within_the_band() {
+        let ttl = Duration::from_secs(1000);
+        let now = Instant::now();
+        let first = now + jittered(ttl, 10);
+        let second = now + jittered(ttl, 10);
+        assert_ne!(first, second);
+        for expires_at in [first, second] {
+            let ttl = expires_at - now;
+            assert!(ttl >= Duration::from_secs(900) && ttl <= Duration::from_secs(1100));
+        }
+        assert_eq!(jittered(ttl, 0), ttl);
+    }
+
+    #[test]
+    fn valid_payload_needs_page_url_or_links() {
+        assert!(valid_payload(br#"{"pageUrl":"https://song.link/s/1"}"#));
+        assert!(valid_payload(br#"{"linksByPlatform":{}}"#));
+        assert!(!valid_payload(b"{}"));
+        assert!(!valid_payload(br#"{"pageUrl":null,"linksByPlatform":[]}"#));
+        assert!(!valid_payload(b"not json"));
+    }
 }
@@ -0,0 +1,577 @@
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+};
+use futures::future::{BoxFuture, FutureExt};
+use rand::Rng;
+use reqwest::{Client, Request, Response, Url};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::{env_or, ErrorCode, ErrorDetails, Metrics, TraceContext, TRACE_CONTEXT};
+
+const UPSTREAM_HOST: &str = "https://api.song.link";
+const DEFAULT_API_VERSION: &str = "v1-alpha.1";
+const KNOWN_API_VERSIONS: &[&str] = &["v1-alpha.1", "v1"];
+const DEFAULT_UPSTREAM_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_POOL_MAX_IDLE: usize = 10;
+const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+const MAX_UPSTREAM_REDIRECTS: usize = 3;
+const DEFAULT_HTTP2_KEEPALIVE_INTERVAL_SECS: u64 = 30;
+const DEFAULT_HTTP2_KEEPALIVE_TIMEOUT_SECS: u64 = 20;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY_MS: u64 = 100;
+const RETRY_MAX_DELAY_MS: u64 = 2_000;
+const ERROR_SNIPPET_BYTES: usize = 512;
+
+/// Path of the links endpoint, relative to the API root.
+pub const LINKS_PATH: &str = "/links";
+
+const FORWARDED_UPSTREAM_HEADERS: [&str; 3] =
+    ["retry-after", "x-ratelimit-remaining", "x-ratelimit-reset"];
+
+/// Sends one request. `reqwest::Client` is the real implementation; tests
+/// substitute canned responses to exercise the retry loop.
+pub trait Transport: Send + Sync {
+    fn execute(&self, request: Request) -> BoxFuture<'_, reqwest::Result<Response>>;
+}
+
+impl Transport for Client {
+    fn execute(&self, request: Request) -> BoxFuture<'_, reqwest::Result<Response>> {
+        Client::execute(self, request).boxed()
+    }
+}
+
+/// HTTP client for the Songlink API and its fallback. It knows how to
+/// reach them, retry, and read a response; caching and API key rotation
+/// stay with the caller.
+pub struct SonglinkClient<T = Client> {
+    http: T,
+    /// API root such as `https://api.song.link/v1-alpha.1`, without the
+    /// endpoint path.
+    root: String,
+    fallback_root: Option<String>,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
+    max_attempts: u32,
+    max_body_bytes: usize,
+}
+
+/// An upstream answer with a body that parsed as JSON. Error statuses are
+/// returned here too, so the caller decides what to forward and cache.
+pub struct Fetched {
+    pub status: StatusCode,
+    /// The subset of upstream headers forwarded to clients.
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub content_type: HeaderValue,
+    pub upstream_etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+}
+
+#[derive(Clone)]
+pub struct FetchError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<ErrorDetails>,
+}
+
+impl SonglinkClient {
+    pub fn from_env() -> Self {
+        let timeout = Duration::from_secs(env_or(
+            "PROXY_UPSTREAM_TIMEOUT_SECS",
+            DEFAULT_UPSTREAM_TIMEOUT_SECS,
+        ));
+        let connect_timeout = Some(Duration::from_secs(env_or("PROXY_CONNECT_TIMEOUT_SECS", 0)))
+            .filter(|connect_timeout| !connect_timeout.is_zero());
+        // A connect timeout above the total one would never fire, which is
+        // almost certainly a swapped pair of values.
+        if let Some(connect_timeout) = connect_timeout {
+            if !timeout.is_zero() && connect_timeout > timeout {
+                tracing::error!(
+                    connect_timeout_secs = connect_timeout.as_secs(),
+                    timeout_secs = timeout.as_secs(),
+                    "PROXY_CONNECT_TIMEOUT_SECS must not exceed PROXY_UPSTREAM_TIMEOUT_SECS"
+                );
+                std::process::exit(1);
+            }
+        }
+
+        let user_agent = std::env::var("PROXY_USER_AGENT").unwrap_or_else(|_| {
+            concat!("songlink-cors-proxy/", env!("CARGO_PKG_VERSION")).to_string()
+        });
+
+        let mut client = Client::builder()
+            .user_agent(user_agent)
+            .gzip(true)
+            .brotli(true)
+            .deflate(true)
+            .pool_max_idle_per_host(env_or("PROXY_POOL_MAX_IDLE", DEFAULT_POOL_MAX_IDLE))
+            .pool_idle_timeout(Duration::from_secs(env_or(
+                "PROXY_POOL_IDLE_TIMEOUT_SECS",
+                DEFAULT_POOL_IDLE_TIMEOUT_SECS,
+            )))
+            .http2_keep_alive_interval(Some(Duration::from_secs(env_or(
+                "PROXY_HTTP2_KEEPALIVE_INTERVAL_SECS",
+                DEFAULT_HTTP2_KEEPALIVE_INTERVAL_SECS,
+            ))))
+            .http2_keep_alive_timeout(Duration::from_secs(env_or(
+                "PROXY_HTTP2_KEEPALIVE_TIMEOUT_SECS",
+                DEFAULT_HTTP2_KEEPALIVE_TIMEOUT_SECS,
+            )));
+        // Zero leaves upstream requests unbounded rather than failing each one
+        // the moment it starts.
+        if !timeout.is_zero() {
+            client = client.timeout(timeout);
+        }
+        if let Some(connect_timeout) = connect_timeout {
+            client = client.connect_timeout(connect_timeout);
+        }
+        client = with_http_version(client);
+        let root = upstream_root();
+        let fallback_root = std::env::var("PROXY_FALLBACK_UPSTREAM")
+            .ok()
+            .filter(|base| !base.is_empty())
+            .map(api_root);
+        let origins = [Some(&root), fallback_root.as_ref()];
+        client = client.redirect(redirect_policy(origins.into_iter().flatten()));
+        if let Some(proxy) = egress_proxy() {
+            client = client.proxy(proxy);
+        }
+
+        Self {
+            http: client.build().expect("Failed to create HTTP client"),
+            root,
+            fallback_root,
+            timeout,
+            connect_timeout,
+            max_attempts: env_or("PROXY_MAX_RETRIES", DEFAULT_MAX_ATTEMPTS).max(1),
+            max_body_bytes: env_or("PROXY_MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES),
+        }
+    }
+}
+
+impl<T: Transport> SonglinkClient<T> {
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Full URL of an endpoint, e.g. `LINKS_PATH`.
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.root, path)
+    }
+
+    pub fn fallback_root(&self) -> Option<&str> {
+        self.fallback_root.as_deref()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    pub async fn reachable(&self, timeout: Duration) -> bool {
+        let Ok(url) = Url::parse(&self.endpoint(LINKS_PATH)) else {
+            return false;
+        };
+        let mut request = Request::new(Method::HEAD, url);
+        *request.timeout_mut() = Some(timeout);
+        self.http.execute(request).await.is_ok()
+    }
+
+    /// Requests `url` and reads the response. A `304` answer to a
+    /// conditional request is returned with an empty body.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        conditional: &HeaderMap,
+        metrics: &Metrics,
+    ) -> Result<Fetched, FetchError> {
+        let failed = |code: ErrorCode, message: String, details: Option<ErrorDetails>| {
+            metrics.upstream_errors.fetch_add(1, Ordering::Relaxed);
+            FetchError {
+                status: StatusCode::BAD_GATEWAY,
+                code,
+                message,
+                details,
+            }
+        };
+
+        let url = Url::parse(url).map_err(|e| {
+            failed(
+                ErrorCode::UpstreamUnreachable,
+                format!("Failed to fetch from Songlink API: {}", e),
+                None,
+            )
+        })?;
+
+        let response = self.send(url, conditional, metrics).await.map_err(|e| {
+            let code = if e.is_timeout() {
+                ErrorCode::UpstreamTimeout
+            } else if e.is_redirect() {
+                ErrorCode::UpstreamBadStatus
+            } else {
+                ErrorCode::UpstreamUnreachable
+            };
+            let message = match std::error::Error::source(&e).filter(|_| e.is_redirect()) {
+                Some(reason) => format!("Songlink API redirect rejected: {}", reason),
+                None => format!("Failed to fetch from Songlink API: {}", e),
+            };
+            failed(code, message, None)
+        })?;
+
+        let status = response.status();
+
+        let mut headers = HeaderMap::new();
+        for name in FORWARDED_UPSTREAM_HEADERS {
+            if let Some(value) = response.headers().get(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .cloned()
+            .unwrap_or_else(|| HeaderValue::from_static("application/json"));
+        let upstream_etag = response.headers().get(header::ETAG).cloned();
+        let last_modified = response.headers().get(header::LAST_MODIFIED).cloned();
+
+        let body = if status == StatusCode::NOT_MODIFIED && !conditional.is_empty() {
+            Bytes::new()
+        } else {
+            let body = read_body(response, self.max_body_bytes)
+                .await
+                .map_err(|(code, e)| {
+                    failed(code, format!("Failed to read response: {}", e), None)
+                })?;
+
+            if let Err(e) = serde_json::from_slice::<serde::de::IgnoredAny>(&body) {
+                let snippet = String::from_utf8_lossy(&body[..body.len().min(ERROR_SNIPPET_BYTES)]);
+                return Err(failed(
+                    ErrorCode::ParseError,
+                    format!("Failed to parse response: {}", e),
+                    Some(ErrorDetails {
+                        upstream_status: status.as_u16(),
+                        body: snippet.into_owned(),
+                    }),
+                ));
+            }
+            body
+        };
+
+        Ok(Fetched {
+            status,
+            headers,
+            body,
+            content_type,
+            upstream_etag,
+            last_modified,
+        })
+    }
+
+    async fn send(
+        &self,
+        url: Url,
+        headers: &HeaderMap,
+        metrics: &Metrics,
+    ) -> reqwest::Result<Response> {
+        let deadline = Some(Instant::now() + self.timeout).filter(|_| !self.timeout.is_zero());
+        let mut attempt = 1;
+        let traceparent = TRACE_CONTEXT
+            .try_with(|context| context.traceparent())
+            .unwrap_or_else(|_| TraceContext::new().traceparent());
+
+        loop {
+            let started = Instant::now();
+            let mut request = Request::new(Method::GET, url.clone());
+            *request.headers_mut() = headers.clone();
+            if let Ok(traceparent) = HeaderValue::from_str(&traceparent) {
+                request.headers_mut().insert("traceparent", traceparent);
+            }
+            *request.timeout_mut() =
+                deadline.map(|deadline| deadline.saturating_duration_since(started));
+            let result = self.http.execute(request).await;
+            metrics.observe_upstream_latency(started.elapsed());
+
+            let retryable = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            };
+
+            if !retryable || attempt >= self.max_attempts {
+                return result;
+            }
+
+            let backoff = retry_backoff(attempt);
+            if deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+                return result;
+            }
+
+            tracing::debug!(
+                attempt,
+                backoff_ms = backoff.as_millis() as u64,
+                "Retrying upstream request"
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn upstream_root() -> String {
+    if let Ok(base) = std::env::var("PROXY_UPSTREAM_BASE") {
+        return api_root(base);
+    }
+
+    let version =
+        std::env::var("PROXY_API_VERSION").unwrap_or_else(|_| DEFAULT_API_VERSION.to_string());
+
+    if !KNOWN_API_VERSIONS.contains(&version.as_str()) {
+        tracing::error!(
+            version,
+            known = ?KNOWN_API_VERSIONS,
+            "Unknown PROXY_API_VERSION"
+        );
+        std::process::exit(1);
+    }
+
+    format!("{}/{}", UPSTREAM_HOST, version)
+}
+
+/// Bases used to be the links endpoint itself, so a trailing `/links` is
+/// dropped to keep those configurations working.
+fn api_root(base: String) -> String {
+    let base = base.trim_end_matches('/');
+    base.strip_suffix(LINKS_PATH).unwrap_or(base).to_string()
+}
+
+/// Songlink isn't expected to redirect at all, so only a few hops on the
+/// upstreams' own hosts are followed.
+fn redirect_policy<'a>(bases: impl Iterator<Item = &'a String>) -> reqwest::redirect::Policy {
+    let origins: Vec<_> = bases
+        .filter_map(|base| reqwest::Url::parse(base).ok())
+        .map(|url| {
+            (
+                url.host_str().map(str::to_string),
+                url.port_or_known_default(),
+            )
+        })
+        .collect();
+
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let target = attempt.url();
+        let target_origin = (
+            target.host_str().map(str::to_string),
+            target.port_or_known_default(),
+        );
+
+        if attempt.previous().len() > MAX_UPSTREAM_REDIRECTS {
+            let message = format!("more than {} redirects", MAX_UPSTREAM_REDIRECTS);
+            attempt.error(message)
+        } else if !origins.contains(&target_origin) {
+            let message = format!(
+                "redirect to unexpected host {}",
+                target_origin.0.unwrap_or_default()
+            );
+            attempt.error(message)
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// `auto` negotiates HTTP/2 via ALPN and falls back to HTTP/1.1.
+fn with_http_version(client: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+    let version = std::env::var("PROXY_HTTP_VERSION").unwrap_or_else(|_| "auto".to_string());
+
+    match version.as_str() {
+        "auto" => client,
+        "http1" => client.http1_only(),
+        "http2" => client.http2_prior_knowledge(),
+        _ => {
+            tracing::error!(
+                version,
+                "Unknown PROXY_HTTP_VERSION, expected auto, http1, or http2"
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn egress_proxy() -> Option<reqwest::Proxy> {
+    let url = std::env::var("PROXY_EGRESS").ok()?;
+
+    let proxy = reqwest::Proxy::all(&url).unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Invalid PROXY_EGRESS");
+        std::process::exit(1);
+    });
+
+    tracing::info!("Routing upstream requests through PROXY_EGRESS");
+    Some(proxy.no_proxy(reqwest::NoProxy::from_env()))
+}
+
+fn retry_backoff(attempt: u32) -> Duration {
+    let base = RETRY_BASE_DELAY_MS.saturating_mul(1 << (attempt - 1).min(10));
+    let base = base.min(RETRY_MAX_DELAY_MS);
+    Duration::from_millis(base + rand::thread_rng().gen_range(0..=base))
+}
+
+async fn read_body(mut response: Response, limit: usize) -> Result<Bytes, (ErrorCode, String)> {
+    let too_large = || {
+        (
+            ErrorCode::ResponseTooLarge,
+            format!("body exceeds {} bytes", limit),
+        )
+    };
+
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| {
+        let code = if e.is_timeout() {
+            ErrorCode::UpstreamTimeout
+        } else {
+            ErrorCode::UpstreamUnreachable
+        };
+        (code, e.to_string())
+    })? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{response::Redirect, routing::get, Router};
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::Mutex;
+
+    /// Answers each request with the next scripted status and a `{}` body.
+    struct Scripted {
+        statuses: Mutex<VecDeque<u16>>,
+        calls: AtomicU32,
+    }
+
+    impl Scripted {
+        fn new(statuses: &[u16]) -> Self {
+            Self {
+                statuses: Mutex::new(statuses.iter().copied().collect()),
+                calls: AtomicU32::new(0),
+            }
+        }
+    }
+
+    impl Transport for Scripted {
+        fn execute(&self, _request: Request) -> BoxFuture<'_, reqwest::Result<Response>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let status = self.statuses.lock().unwrap().pop_front().unwrap_or(200);
+            let response = axum::http::Response::builder()
+                .status(status)
+                .body("{}")
+                .unwrap();
+            async move { Ok(Response::from(response)) }.boxed()
+        }
+    }
+
+    fn client(statuses: &[u16], max_attempts: u32) -> SonglinkClient<Scripted> {
+        SonglinkClient {
+            http: Scripted::new(statuses),
+            root: "http://songlink.test/v1-alpha.1".to_string(),
+            fallback_root: None,
+            timeout: Duration::from_secs(10),
+            connect_timeout: None,
+            max_attempts,
+            max_body_bytes: 1024,
+        }
+    }
+
+    async fn fetch_status(client: &SonglinkClient<Scripted>) -> StatusCode {
+        let url = client.endpoint(LINKS_PATH);
+        let (conditional, metrics) = (HeaderMap::new(), Metrics::default());
+        match client.fetch(&url, &conditional, &metrics).await {
+            Ok(fetched) => fetched.status,
+            Err(e) => panic!("fetch failed: {}", e.message),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_until_success() {
+        let client = client(&[500, 503, 200], 3);
+        assert_eq!(fetch_status(&client).await, StatusCode::OK);
+        assert_eq!(client.http.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_max_attempts() {
+        let client = client(&[500, 502, 200], 2);
+        assert_eq!(fetch_status(&client).await, StatusCode::BAD_GATEWAY);
+        assert_eq!(client.http.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let client = client(&[429, 200], 3);
+        assert_eq!(fetch_status(&client).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(client.http.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn api_root_drops_trailing_links_path() {
+        for base in [
+            "https://mirror.example/v1",
+            "https://mirror.example/v1/",
+            "https://mirror.example/v1/links",
+            "https://mirror.example/v1/links/",
+        ] {
+            assert_eq!(api_root(base.to_string()), "https://mirror.example/v1");
+        }
+        assert_eq!(
+            api_root("https://mirror.example/links-api".to_string()),
+            "https://mirror.example/links-api"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_stay_on_upstream_hosts() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let base = format!("http://127.0.0.1:{}", port);
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/same", get(|| async { Redirect::temporary("/ok") }))
+            .route(
+                "/other",
+                get(move || async move {
+                    Redirect::temporary(&format!("http://localhost:{}/ok", port))
+                }),
+            )
+            .route("/loop", get(|| async { Redirect::temporary("/loop") }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let http = Client::builder()
+            .redirect(redirect_policy([base.clone()].iter()))
+            .build()
+            .unwrap();
+        let get = |path: &str| http.get(format!("{}{}", base, path)).send();
+
+        assert_eq!(get("/same").await.unwrap().status(), StatusCode::OK);
+        assert!(get("/other").await.unwrap_err().is_redirect());
+        assert!(get("/loop").await.unwrap_err().is_redirect());
+    }
+}
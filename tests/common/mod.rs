@@ -0,0 +1,586 @@
+//! Runs the proxy binary against an in-process mock of the Songlink API.
+// Each test crate uses a different subset of the harness.
+#![allow(dead_code)]
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, Version},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const TRACK_URL: &str = "https://open.spotify.com/track/2TmqHjg7uhizGndzXQdFuf";
+pub const NOT_FOUND_URL: &str = "https://open.spotify.com/track/notfound";
+pub const BAD_REQUEST_URL: &str = "https://open.spotify.com/track/badrequest";
+pub const NOT_JSON_URL: &str = "https://open.spotify.com/track/notjson";
+/// Answered with a maintenance page of a few kilobytes.
+pub const HTML_PAGE_URL: &str = "https://open.spotify.com/track/htmlpage";
+/// Answered with a Spotify link only, as if Songlink had not matched it yet.
+pub const PARTIAL_URL: &str = "https://open.spotify.com/track/partial";
+/// Answered with `200 {}`, as Songlink has done during incidents.
+pub const EMPTY_URL: &str = "https://open.spotify.com/track/empty";
+/// Answered with a protocol-relative `pageUrl` and a relative platform link.
+pub const RELATIVE_URL: &str = "https://open.spotify.com/track/relative";
+/// Answered with the usual links in chunks, without a `Content-Length`.
+pub const STREAMED_URL: &str = "https://open.spotify.com/track/streamed";
+/// Answered with `ETag: "v1"` and `Last-Modified`, or `304` when the
+/// lookup sends that ETag back.
+pub const CONDITIONAL_URL: &str = "https://open.spotify.com/track/conditional";
+/// Redirected to another host.
+pub const OFFSITE_REDIRECT_URL: &str = "https://open.spotify.com/track/offsite";
+/// Redirected to the same lookup again, forever.
+pub const REDIRECT_LOOP_URL: &str = "https://open.spotify.com/track/loop";
+/// Redirected on the mock itself to the `TRACK_URL` lookup.
+pub const MOVED_URL: &str = "https://open.spotify.com/track/moved";
+/// Answered with `429`, `Retry-After: 60` and rate-limit headers.
+pub const RATE_LIMITED_URL: &str = "https://open.spotify.com/track/ratelimited";
+/// Links starting with this fail with `503` twice before they are answered.
+pub const FLAKY_URL: &str = "https://open.spotify.com/track/flaky";
+/// Links starting with this are answered after `SLOW_DELAY`.
+pub const SLOW_URL: &str = "https://open.spotify.com/track/slow";
+pub const SLOW_DELAY: Duration = Duration::from_secs(2);
+
+/// A self-signed certificate for `localhost` and `127.0.0.1`, and its key.
+pub const TLS_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert.pem");
+pub const TLS_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/key.pem");
+
+/// Canned Songlink answers, keyed by the `url` the proxy asks for.
+pub mod fixtures {
+    use serde_json::{json, Value};
+
+    pub fn links(url: &str) -> Value {
+        json!({
+            "entityUniqueId": "SPOTIFY_SONG::2TmqHjg7uhizGndzXQdFuf",
+            "userCountry": "US",
+            "pageUrl": "https://song.link/s/2TmqHjg7uhizGndzXQdFuf",
+            "linksByPlatform": {
+                "spotify": { "url": url, "entityUniqueId": "SPOTIFY_SONG::2TmqHjg7uhizGndzXQdFuf" },
+                "tidal": { "url": "https://listen.tidal.com/track/1", "entityUniqueId": "TIDAL_SONG::1" }
+            },
+            "entitiesByUniqueId": {}
+        })
+    }
+
+    pub fn partial(url: &str) -> Value {
+        let mut links = links(url);
+        links["linksByPlatform"]
+            .as_object_mut()
+            .unwrap()
+            .remove("tidal");
+        links
+    }
+
+    pub fn relative(url: &str) -> Value {
+        let mut links = links(url);
+        links["pageUrl"] = json!("//song.link/s/2TmqHjg7uhizGndzXQdFuf");
+        links["linksByPlatform"]["tidal"]["url"] = json!("/t/1");
+        links
+    }
+
+    pub fn html_page() -> String {
+        format!(
+            "<html><body>{}</body></html>",
+            "Down for maintenance. ".repeat(100)
+        )
+    }
+
+    pub fn not_found() -> Value {
+        json!({ "statusCode": 404, "code": "could_not_resolve_entity" })
+    }
+
+    pub fn bad_request() -> Value {
+        json!({ "statusCode": 400, "code": "could_not_fetch_entity_data" })
+    }
+}
+
+#[derive(Default)]
+pub struct MockSonglink {
+    hits: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    last_url: Mutex<Option<String>>,
+    last_query: Mutex<HashMap<String, String>>,
+    last_headers: Mutex<HeaderMap>,
+    last_version: Mutex<Option<Version>>,
+    failing: AtomicBool,
+    /// Lookups seen per link, for answers that change on retry.
+    attempts: Mutex<HashMap<String, usize>>,
+}
+
+impl MockSonglink {
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Most lookups that were ever being answered at the same time.
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn last_url(&self) -> Option<String> {
+        self.last_url.lock().unwrap().clone()
+    }
+
+    /// Query parameters of the last lookup.
+    pub fn last_query(&self) -> HashMap<String, String> {
+        self.last_query.lock().unwrap().clone()
+    }
+
+    /// A request header of the last lookup.
+    pub fn last_header(&self, name: &str) -> Option<String> {
+        self.last_headers
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    }
+
+    /// HTTP version of the last lookup.
+    pub fn last_version(&self) -> Option<Version> {
+        *self.last_version.lock().unwrap()
+    }
+
+    /// While set, every lookup is answered with `500`.
+    pub fn fail(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+}
+
+async fn links(
+    State(mock): State<Arc<MockSonglink>>,
+    Query(params): Query<HashMap<String, String>>,
+    version: Version,
+    headers: HeaderMap,
+) -> Response {
+    mock.hits.fetch_add(1, Ordering::SeqCst);
+    let url = params.get("url").cloned().unwrap_or_default();
+    *mock.last_url.lock().unwrap() = Some(url.clone());
+    *mock.last_query.lock().unwrap() = params.clone();
+    *mock.last_headers.lock().unwrap() = headers;
+    *mock.last_version.lock().unwrap() = Some(version);
+
+    if mock.failing.load(Ordering::SeqCst) {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let attempt = {
+        let mut attempts = mock.attempts.lock().unwrap();
+        let attempt = attempts.entry(url.clone()).or_default();
+        *attempt += 1;
+        *attempt
+    };
+    if url.starts_with(FLAKY_URL) && attempt <= 2 {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    if url.starts_with(SLOW_URL) {
+        let in_flight = mock.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        mock.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(SLOW_DELAY).await;
+        mock.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    match url.as_str() {
+        NOT_FOUND_URL => (StatusCode::NOT_FOUND, axum::Json(fixtures::not_found())).into_response(),
+        BAD_REQUEST_URL => {
+            (StatusCode::BAD_REQUEST, axum::Json(fixtures::bad_request())).into_response()
+        }
+        NOT_JSON_URL => (StatusCode::OK, "<html>maintenance</html>").into_response(),
+        HTML_PAGE_URL => axum::response::Html(fixtures::html_page()).into_response(),
+        RATE_LIMITED_URL => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [
+                ("retry-after", "60"),
+                ("x-ratelimit-remaining", "0"),
+                ("x-ratelimit-reset", "1700000000"),
+                ("set-cookie", "session=upstream"),
+            ],
+            axum::Json(serde_json::json!({ "statusCode": 429, "code": "too_many_requests" })),
+        )
+            .into_response(),
+        EMPTY_URL => axum::Json(serde_json::json!({})).into_response(),
+        PARTIAL_URL => axum::Json(fixtures::partial(&url)).into_response(),
+        RELATIVE_URL => axum::Json(fixtures::relative(&url)).into_response(),
+        OFFSITE_REDIRECT_URL => {
+            axum::response::Redirect::temporary("http://elsewhere.example/links?url=x")
+                .into_response()
+        }
+        REDIRECT_LOOP_URL => axum::response::Redirect::temporary(&format!(
+            "/links?url={}",
+            urlencoding::encode(REDIRECT_LOOP_URL)
+        ))
+        .into_response(),
+        MOVED_URL => axum::response::Redirect::temporary(&format!(
+            "/links?url={}",
+            urlencoding::encode(TRACK_URL)
+        ))
+        .into_response(),
+        CONDITIONAL_URL => {
+            let revalidating = mock
+                .last_headers
+                .lock()
+                .unwrap()
+                .get("if-none-match")
+                .is_some_and(|etag| etag == "\"v1\"");
+            if revalidating {
+                StatusCode::NOT_MODIFIED.into_response()
+            } else {
+                (
+                    [
+                        ("etag", "\"v1\""),
+                        ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                    ],
+                    axum::Json(fixtures::links(&url)),
+                )
+                    .into_response()
+            }
+        }
+        STREAMED_URL => {
+            let body = serde_json::to_vec(&fixtures::links(&url)).unwrap();
+            let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+                body.chunks(64).map(|chunk| Ok(chunk.to_vec())).collect();
+            axum::body::Body::from_stream(futures::stream::iter(chunks)).into_response()
+        }
+        _ => axum::Json(fixtures::links(&url)).into_response(),
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("no free port")
+}
+
+/// Writes `contents` to a file of its own in the temp directory, for
+/// settings that take a path.
+pub fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "songlink-proxy-test-{}-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        name
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// A MaxMind country database, as `PROXY_GEOIP_DB` expects, that places
+/// every IPv4 address in `iso_code`. Its search tree is a single node whose
+/// records both point at the one data record.
+pub fn country_db(iso_code: &str) -> std::path::PathBuf {
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.push(2 << 5 | value.len() as u8);
+        out.extend_from_slice(value.as_bytes());
+    }
+    fn map(out: &mut Vec<u8>, entries: u8) {
+        out.push(7 << 5 | entries);
+    }
+    /// `kind` is 5 for uint16, 6 for uint32 and 9 for uint64.
+    fn uint(out: &mut Vec<u8>, kind: u8, value: u64) {
+        let bytes = value.to_be_bytes();
+        let bytes = &bytes[bytes.iter().take_while(|byte| **byte == 0).count()..];
+        if kind < 8 {
+            out.push(kind << 5 | bytes.len() as u8);
+        } else {
+            out.extend_from_slice(&[bytes.len() as u8, kind - 7]);
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    // Records past the node count point into the data section, offset by
+    // the node count and the 16-byte separator.
+    let mut db = vec![0, 0, 17, 0, 0, 17];
+    db.extend_from_slice(&[0; 16]);
+
+    map(&mut db, 1);
+    string(&mut db, "country");
+    map(&mut db, 1);
+    string(&mut db, "iso_code");
+    string(&mut db, iso_code);
+
+    db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    map(&mut db, 9);
+    string(&mut db, "node_count");
+    uint(&mut db, 6, 1);
+    string(&mut db, "record_size");
+    uint(&mut db, 5, 24);
+    string(&mut db, "ip_version");
+    uint(&mut db, 5, 4);
+    string(&mut db, "database_type");
+    string(&mut db, "GeoLite2-Country");
+    string(&mut db, "languages");
+    db.extend_from_slice(&[1, 4]);
+    string(&mut db, "en");
+    string(&mut db, "binary_format_major_version");
+    uint(&mut db, 5, 2);
+    string(&mut db, "binary_format_minor_version");
+    uint(&mut db, 5, 0);
+    string(&mut db, "build_epoch");
+    uint(&mut db, 9, 0);
+    string(&mut db, "description");
+    map(&mut db, 1);
+    string(&mut db, "en");
+    string(&mut db, "Test countries");
+
+    temp_file("country.mmdb", &db)
+}
+
+/// A forward proxy that answers every request itself with the `TRACK_URL`
+/// links, recording the URI it was asked for. Returns its URL and the
+/// recorded URIs.
+pub async fn forward_proxy() -> (String, Arc<Mutex<Vec<String>>>) {
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new().fallback({
+        let requested = requested.clone();
+        move |uri: axum::http::Uri| async move {
+            requested.lock().unwrap().push(uri.to_string());
+            axum::Json(fixtures::links(TRACK_URL))
+        }
+    });
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{}", addr), requested)
+}
+
+/// The proxy binary listening on `bind` with its output discarded. `env`
+/// is applied last, so it overrides anything set here.
+fn proxy_command<'a>(bind: &str, env: impl IntoIterator<Item = &'a (&'a str, &'a str)>) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_songlink-proxy"));
+    command
+        .env("PROXY_BIND_ADDR", bind)
+        .env("RUST_LOG", "error")
+        .envs(env.into_iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    command
+}
+
+/// Starts the proxy with `env` and returns its exit status if it stops
+/// within a few seconds, as it does on invalid configuration.
+pub fn exit_status_with(env: &[(&str, &str)]) -> Option<std::process::ExitStatus> {
+    let bind = format!("127.0.0.1:{}", free_port());
+    let mut proxy = proxy_command(&bind, env)
+        .spawn()
+        .expect("failed to start the proxy");
+
+    for _ in 0..100 {
+        if let Some(status) = proxy.try_wait().unwrap() {
+            return Some(status);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let _ = proxy.kill();
+    let _ = proxy.wait();
+    None
+}
+
+/// Starts the proxy with `env` and collects its JSON log lines up to the
+/// one announcing that it is listening.
+pub fn startup_log(env: &[(&str, &str)]) -> Vec<serde_json::Value> {
+    let bind = format!("127.0.0.1:{}", free_port());
+    let mut proxy = proxy_command(&bind, [("RUST_LOG", "info")].iter().chain(env))
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start the proxy");
+
+    let mut lines = Vec::new();
+    for line in BufReader::new(proxy.stdout.take().unwrap()).lines() {
+        let line: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+        let listening = line["fields"]["message"]
+            .as_str()
+            .is_some_and(|message| message.contains("running on"));
+        lines.push(line);
+        if listening {
+            break;
+        }
+    }
+
+    let _ = proxy.kill();
+    let _ = proxy.wait();
+    lines
+}
+
+/// A running proxy pointed at its own mock upstream; killed on drop.
+pub struct Harness {
+    pub mock: Arc<MockSonglink>,
+    pub client: reqwest::Client,
+    /// Base URL of the mock, for pointing another proxy at it.
+    pub upstream: String,
+    base: String,
+    proxy: Child,
+    logs: Arc<Mutex<Vec<String>>>,
+}
+
+impl Harness {
+    pub async fn start() -> Self {
+        Self::start_with(&[]).await
+    }
+
+    pub async fn start_with(env: &[(&str, &str)]) -> Self {
+        Self::spawn(env, false).await
+    }
+
+    /// Like `start_with`, but keeps the proxy's `info` log lines for `logs`.
+    pub async fn start_logged(env: &[(&str, &str)]) -> Self {
+        Self::spawn(env, true).await
+    }
+
+    async fn spawn(env: &[(&str, &str)], logged: bool) -> Self {
+        let mock = Arc::new(MockSonglink::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream: SocketAddr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/links", get(links))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let bind = format!("127.0.0.1:{}", free_port());
+        let upstream = format!("http://{}", upstream);
+        let defaults = [
+            ("PROXY_UPSTREAM_BASE", upstream.as_str()),
+            ("PROXY_MAX_RETRIES", "1"),
+            ("PROXY_CACHE_TTL_JITTER_PCT", "0"),
+        ];
+        let mut command = proxy_command(&bind, defaults.iter().chain(env));
+        if logged {
+            command.env("RUST_LOG", "info").stdout(Stdio::piped());
+        }
+        let mut proxy = command.spawn().expect("failed to start the proxy");
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        if let Some(stdout) = proxy.stdout.take() {
+            let logs = logs.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    logs.lock().unwrap().push(line);
+                }
+            });
+        }
+
+        // The TLS fixtures are self-signed.
+        let tls = env.iter().any(|(name, _)| *name == "PROXY_TLS_CERT");
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(tls)
+            .build()
+            .unwrap();
+        let harness = Self {
+            mock,
+            client,
+            upstream,
+            base: format!("{}://{}", if tls { "https" } else { "http" }, bind),
+            proxy,
+            logs,
+        };
+        harness.wait_until_up().await;
+        harness
+    }
+
+    async fn wait_until_up(&self) {
+        for _ in 0..100 {
+            if self.client.get(self.url("/health")).send().await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("proxy did not start");
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// The Prometheus metrics page.
+    pub async fn metrics(&self) -> String {
+        self.client
+            .get(self.url("/metrics"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// Log lines written so far by a proxy started with `start_logged`.
+    pub fn logs(&self) -> Vec<String> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    /// Waits for a log line matching `matches` and returns it parsed.
+    pub async fn wait_for_log(
+        &self,
+        matches: impl Fn(&serde_json::Value) -> bool,
+    ) -> serde_json::Value {
+        for _ in 0..100 {
+            let found = self
+                .logs()
+                .iter()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .find(|line| matches(line));
+            if let Some(line) = found {
+                return line;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no matching log line");
+    }
+
+    /// Sends SIGTERM, as a container runtime does on stop.
+    pub fn terminate(&self) {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.proxy.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Waits up to `timeout` for the proxy to exit.
+    pub async fn exit_status(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        let started = std::time::Instant::now();
+        while started.elapsed() < timeout {
+            if let Some(status) = self.proxy.try_wait().unwrap() {
+                return Some(status);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+
+    /// `/cache/stats`, for a proxy without an admin token.
+    pub async fn cache_stats(&self) -> serde_json::Value {
+        self.client
+            .get(self.url("/cache/stats"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    pub async fn links(&self, url: &str) -> reqwest::Response {
+        self.client
+            .get(self.url("/api/links"))
+            .query(&[("url", url)])
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.proxy.kill();
+        let _ = self.proxy.wait();
+    }
+}
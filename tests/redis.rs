@@ -0,0 +1,139 @@
+//! Needs a Redis server: `cargo test --features redis-tests`, with
+//! `REDIS_TEST_URL` pointing at it (defaults to `redis://127.0.0.1:6379`).
+#![cfg(feature = "redis-tests")]
+
+mod common;
+
+use common::{Harness, TRACK_URL};
+use serde_json::Value;
+
+fn redis_url() -> String {
+    std::env::var("REDIS_TEST_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string())
+}
+
+/// A namespace of its own per test, so tests sharing one Redis don't see
+/// each other's entries.
+fn namespace() -> String {
+    format!("test-{}", rand::random::<u64>())
+}
+
+#[tokio::test]
+async fn replicas_share_the_redis_cache() {
+    let redis = redis_url();
+    let namespace = namespace();
+    let env = [
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", namespace.as_str()),
+    ];
+    let first = Harness::start_with(&env).await;
+    assert_eq!(first.links(TRACK_URL).await.headers()["x-cache"], "MISS");
+
+    let second = Harness::start_with(&[
+        env[0],
+        env[1],
+        ("PROXY_UPSTREAM_BASE", first.upstream.as_str()),
+    ])
+    .await;
+    let response = second.links(TRACK_URL).await;
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["linksByPlatform"]["spotify"]["url"], TRACK_URL);
+    assert_eq!(first.mock.hits(), 1);
+    assert_eq!(second.mock.hits(), 0);
+}
+
+#[tokio::test]
+async fn purge_removes_redis_entries() {
+    let redis = redis_url();
+    let namespace = namespace();
+    let harness = Harness::start_with(&[
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", namespace.as_str()),
+    ])
+    .await;
+    harness.links(TRACK_URL).await;
+
+    let stats: Value = harness
+        .client
+        .get(harness.url("/cache/stats"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(stats["entries"].as_u64().unwrap() >= 1);
+    assert_eq!(stats["capacity"], Value::Null);
+
+    let purged: Value = harness
+        .client
+        .post(harness.url("/cache/purge"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(purged["purged"], 1);
+    assert_eq!(harness.links(TRACK_URL).await.headers()["x-cache"], "MISS");
+}
+
+#[tokio::test]
+async fn namespaces_prefix_keys_and_keep_entries_apart() {
+    let redis = redis_url();
+    let staging = namespace();
+    let production = namespace();
+    let first = Harness::start_with(&[
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", staging.as_str()),
+    ])
+    .await;
+    assert_eq!(first.links(TRACK_URL).await.headers()["x-cache"], "MISS");
+
+    let mut connection = redis::Client::open(redis.as_str())
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+        .unwrap();
+    let keys: Vec<String> = redis::cmd("KEYS")
+        .arg(format!("songlink-proxy:{}:*", staging))
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 1);
+    assert!(keys[0].starts_with(&format!("songlink-proxy:{}:http", staging)));
+
+    let second = Harness::start_with(&[
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", production.as_str()),
+        ("PROXY_UPSTREAM_BASE", first.upstream.as_str()),
+    ])
+    .await;
+    assert_eq!(second.links(TRACK_URL).await.headers()["x-cache"], "MISS");
+    assert_eq!(first.mock.hits(), 2);
+    assert_eq!(first.links(TRACK_URL).await.headers()["x-cache"], "HIT");
+}
+
+#[tokio::test]
+async fn entry_counts_stay_within_the_namespace() {
+    let redis = redis_url();
+    let (staging, production) = (namespace(), namespace());
+    let staging = Harness::start_with(&[
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", staging.as_str()),
+    ])
+    .await;
+    let production = Harness::start_with(&[
+        ("PROXY_REDIS_URL", redis.as_str()),
+        ("PROXY_CACHE_NAMESPACE", production.as_str()),
+    ])
+    .await;
+
+    staging.links(TRACK_URL).await;
+    for n in 0..2 {
+        production.links(&format!("{}{}", TRACK_URL, n)).await;
+    }
+
+    assert_eq!(staging.cache_stats().await["entries"], 1);
+    assert_eq!(production.cache_stats().await["entries"], 2);
+}